@@ -1,17 +1,79 @@
 use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_cdk::api::caller;
 use ic_cdk_macros::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
 use serde::Serialize;
+use serde_json::{json, Value};
+use ic_certified_map::{AsHashTree, Hash, RbTree};
+use sha2::{Digest, Sha256};
 use num_traits::cast::ToPrimitive;
+use std::time::Duration;
 
-const STAKING_RATE: f64 = 0.10;
-const BORROW_RATE: f64 = 0.12;
-const LENDING_REWARD: f64 = 0.05;
-const YIELD_FARMING_REWARD: f64 = 0.15;
+const DEFAULT_STAKING_RATE: f64 = 0.10;
+const DEFAULT_BORROW_RATE: f64 = 0.12;
+const DEFAULT_LENDING_RATE: f64 = 0.05;
 const SECONDS_IN_YEAR: u64 = 31_536_000;
 
-const COLLATERAL_RATIO: f64 = 2.0;
+// Yield farming emissions (MasterChef-style reward-per-share accounting)
+const REWARD_INDEX_SCALE: u128 = 1_000_000_000_000;
+const DEFAULT_FARMING_REWARD_PER_SECOND: u64 = 1;
+const DEFAULT_FARMING_HALVING_INTERVAL_SECS: u64 = SECONDS_IN_YEAR;
+const MAX_HALVINGS: u64 = 64;
+
+// Share of a locked stake forfeited to the remaining stakers on early exit
+const DEFAULT_EARLY_EXIT_PENALTY_RATE: f64 = 0.10;
+
+const DEFAULT_UNBONDING_DELAY_SECS: u64 = 7 * 86_400;
+
+const AUTO_COMPOUND_INTERVAL_SECS: u64 = 86_400;
+
+const DEFAULT_COLLATERAL_RATIO: f64 = 2.0;
+
+// Share of realized borrow interest kept by the protocol as reserves
+const DEFAULT_RESERVE_FACTOR: f64 = 0.10;
+// Share of farming emissions routed to the treasury
+const DEFAULT_FARMING_FEE_RATE: f64 = 0.0;
+// Share of interest reserves routed to the insurance fund instead of the treasury
+const DEFAULT_INSURANCE_RESERVE_SHARE: f64 = 0.50;
+// Extra collateral seized on liquidation and paid to the liquidator
+const DEFAULT_LIQUIDATION_BONUS: f64 = 0.05;
+
+// Hard safety bounds for admin-updatable parameters
+const MAX_STAKING_RATE: f64 = 0.50;
+const MAX_LENDING_RATE: f64 = 0.50;
+const MAX_BORROW_RATE: f64 = 1.00;
+const MIN_COLLATERAL_RATIO: f64 = 1.1;
+const MAX_COLLATERAL_RATIO: f64 = 5.0;
+const MAX_EARLY_EXIT_PENALTY_RATE: f64 = 0.50;
+const MAX_UNBONDING_DELAY_SECS: u64 = 30 * 86_400;
+const MAX_RESERVE_FACTOR: f64 = 0.50;
+const MAX_FARMING_FEE_RATE: f64 = 0.20;
+const MAX_LIQUIDATION_BONUS: f64 = 0.20;
+
+const RATE_LIMIT_PRUNE_INTERVAL_SECS: u64 = 3_600;
+
+const CYCLES_SAMPLE_INTERVAL_SECS: u64 = 3_600;
+// One week of hourly samples
+const MAX_CYCLES_SAMPLES: usize = 168;
+// Below this balance only withdrawals and repayments are served
+const DEFAULT_SAFE_MODE_THRESHOLD_CYCLES: u128 = 500_000_000_000;
+const MAX_CYCLES_TOP_UPS: usize = 1_000;
+
+// Smallest deposit, loan or lending position accepted, so positions can always cover a transfer fee
+const DEFAULT_MIN_POSITION_SIZE: u64 = 100 * CKBTC_TRANSFER_FEE;
+
+const DEFAULT_TIMELOCK_DELAY_SECS: u64 = 2 * 86_400;
+const MAX_TIMELOCK_DELAY_SECS: u64 = 30 * 86_400;
+
+// Staker governance
+const GOVERNANCE_VOTING_PERIOD_SECS: u64 = 3 * 86_400;
+// Share of total voting power that must take part for a proposal to pass
+const GOVERNANCE_QUORUM: f64 = 0.10;
+// Minimum voting power (sats) needed to submit a proposal
+const GOVERNANCE_MIN_PROPOSAL_POWER: u64 = 100_000;
+// Each proposal snapshots every user and holds a timer, so one proposer can only have a few open at once
+const MAX_OPEN_PROPOSALS_PER_PROPOSER: usize = 2;
 
 const CKBTC_TRANSFER_FEE: u64 = 10; 
 
@@ -92,6 +154,38 @@ enum TransferFromError {
     GenericError { error_code: Nat, message: String },
 }
 
+// Variant names used as metric labels
+impl TransferError {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            TransferError::BadFee { .. } => "BadFee",
+            TransferError::BadBurn { .. } => "BadBurn",
+            TransferError::InsufficientFunds { .. } => "InsufficientFunds",
+            TransferError::TooOld => "TooOld",
+            TransferError::CreatedInFuture { .. } => "CreatedInFuture",
+            TransferError::Duplicate { .. } => "Duplicate",
+            TransferError::TemporarilyUnavailable => "TemporarilyUnavailable",
+            TransferError::GenericError { .. } => "GenericError",
+        }
+    }
+}
+
+impl TransferFromError {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            TransferFromError::BadFee { .. } => "BadFee",
+            TransferFromError::BadBurn { .. } => "BadBurn",
+            TransferFromError::InsufficientFunds { .. } => "InsufficientFunds",
+            TransferFromError::InsufficientAllowance { .. } => "InsufficientAllowance",
+            TransferFromError::TooOld => "TooOld",
+            TransferFromError::CreatedInFuture { .. } => "CreatedInFuture",
+            TransferFromError::Duplicate { .. } => "Duplicate",
+            TransferFromError::TemporarilyUnavailable => "TemporarilyUnavailable",
+            TransferFromError::GenericError { .. } => "GenericError",
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize, Clone, Debug)]
 enum ApproveError {
     BadFee { expected_fee: Nat },
@@ -118,6 +212,777 @@ struct UserData {
     lend_timestamp: Option<u64>,
     farm_timestamp: Option<u64>,
     loan_timestamp: Option<u64>,
+    // Portion of `loans` that is capitalized interest rather than borrowed principal
+    loan_interest: u64,
+    farm_reward_index: u128,
+    farm_pending_rewards: u64,
+    accrued_rewards: HashMap<Principal, u64>,
+    stake_positions: Vec<StakePosition>,
+    pending_unbonds: Vec<UnbondEntry>,
+    auto_compound: bool,
+    privacy: PrivacyLevel,
+    // Principals allowed to read this user's data while it is private
+    authorized_viewers: Vec<Principal>,
+    operators: Vec<OperatorGrant>,
+}
+
+// What an operator may do with someone else's position; moving funds out of the protocol is never delegated
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum OperatorPermission {
+    RepayOnly,
+    CompoundOnly,
+    FullExceptWithdraw,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct OperatorGrant {
+    operator: Principal,
+    permission: OperatorPermission,
+    granted_at: u64,
+    // Nanosecond timestamp after which the grant stops working; `None` never expires
+    expires_at: Option<u64>,
+}
+
+// How an update method touches a position, for checking operator grants.
+// Withdraw covers anything that starts moving funds out, including unstaking and penalized early exits.
+#[derive(Clone, Copy, Debug)]
+enum DelegatedAction {
+    Repay,
+    Compound,
+    Manage,
+    Withdraw,
+}
+
+impl OperatorPermission {
+    fn allows(self, action: DelegatedAction) -> bool {
+        match (self, action) {
+            (_, DelegatedAction::Withdraw) => false,
+            (OperatorPermission::FullExceptWithdraw, _) => true,
+            (OperatorPermission::RepayOnly, DelegatedAction::Repay) => true,
+            (OperatorPermission::CompoundOnly, DelegatedAction::Compound) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum PrivacyLevel {
+    Public,
+    #[default]
+    Private,
+}
+
+// Unstaked funds waiting out the unbonding delay; they earn nothing in the meantime
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct UnbondEntry {
+    id: u64,
+    amount: u64,
+    requested_at: u64,
+    available_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+enum LockPeriod {
+    Days30,
+    Days90,
+    Days180,
+    Days365,
+}
+
+impl LockPeriod {
+    fn days(self) -> u64 {
+        match self {
+            LockPeriod::Days30 => 30,
+            LockPeriod::Days90 => 90,
+            LockPeriod::Days180 => 180,
+            LockPeriod::Days365 => 365,
+        }
+    }
+
+    fn rate_multiplier(self) -> f64 {
+        match self {
+            LockPeriod::Days30 => 1.1,
+            LockPeriod::Days90 => 1.25,
+            LockPeriod::Days180 => 1.5,
+            LockPeriod::Days365 => 2.0,
+        }
+    }
+}
+
+// A locked stake; earns the staking rate times the tier multiplier until `unlock_time`, the base rate afterwards
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct StakePosition {
+    id: u64,
+    amount: u64,
+    lock_period: LockPeriod,
+    start_time: u64,
+    unlock_time: u64,
+    reward_timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum ClaimMode {
+    // Credit rewards to the internal balance without a ledger call
+    ToBalance,
+    // Transfer rewards out of the canister, net of the ledger fee; nothing is credited internally
+    Withdraw,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LockTier {
+    lock_period: LockPeriod,
+    days: u64,
+    rate_multiplier: f64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum RewardStream {
+    Staking,
+    Lending,
+    Farming,
+}
+
+// ICRC-1 ledger a reward stream pays out from
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct RewardTokenConfig {
+    ledger: Principal,
+    symbol: String,
+    fee: u64,
+}
+
+// A constant emission rate that applies from `start_time` (ns) until the next phase starts
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct EmissionPhase {
+    start_time: u64,
+    reward_per_second: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+enum EmissionSchedule {
+    Halving {
+        start_time: u64,
+        initial_reward_per_second: u64,
+        halving_interval_secs: u64,
+    },
+    Curve(Vec<EmissionPhase>),
+}
+
+// Farming rewards are shared pro rata via an accumulated reward-per-share index
+#[derive(Default, Clone)]
+struct FarmingPool {
+    schedule: Option<EmissionSchedule>,
+    acc_reward_per_share: u128,
+    last_update: u64,
+    total_farmed: u64,
+    // Farming fees skimmed from emissions, not yet moved into the treasury
+    accrued_fees: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct FarmingPoolInfo {
+    schedule: Option<EmissionSchedule>,
+    current_reward_per_second: u64,
+    acc_reward_per_share: u128,
+    total_farmed: u64,
+    current_apr: f64,
+}
+
+// Economic and risk parameters; rates are annual fractions
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct ProtocolParams {
+    staking_rate: f64,
+    borrow_rate: f64,
+    lending_rate: f64,
+    collateral_ratio: f64,
+    early_exit_penalty_rate: f64,
+    unbonding_delay_secs: u64,
+    reserve_factor: f64,
+    farming_fee_rate: f64,
+    insurance_reserve_share: f64,
+    liquidation_bonus: f64,
+}
+
+impl Default for ProtocolParams {
+    fn default() -> Self {
+        ProtocolParams {
+            staking_rate: DEFAULT_STAKING_RATE,
+            borrow_rate: DEFAULT_BORROW_RATE,
+            lending_rate: DEFAULT_LENDING_RATE,
+            collateral_ratio: DEFAULT_COLLATERAL_RATIO,
+            early_exit_penalty_rate: DEFAULT_EARLY_EXIT_PENALTY_RATE,
+            unbonding_delay_secs: DEFAULT_UNBONDING_DELAY_SECS,
+            reserve_factor: DEFAULT_RESERVE_FACTOR,
+            farming_fee_rate: DEFAULT_FARMING_FEE_RATE,
+            insurance_reserve_share: DEFAULT_INSURANCE_RESERVE_SHARE,
+            liquidation_bonus: DEFAULT_LIQUIDATION_BONUS,
+        }
+    }
+}
+
+// Fields left as `None` keep their current value
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct ProtocolParamsUpdate {
+    staking_rate: Option<f64>,
+    borrow_rate: Option<f64>,
+    lending_rate: Option<f64>,
+    collateral_ratio: Option<f64>,
+    early_exit_penalty_rate: Option<f64>,
+    unbonding_delay_secs: Option<u64>,
+    reserve_factor: Option<f64>,
+    farming_fee_rate: Option<f64>,
+    insurance_reserve_share: Option<f64>,
+    liquidation_bonus: Option<f64>,
+}
+
+// Access control roles; the owner implicitly holds every role
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Role {
+    Owner,
+    // May pause the contract, but not unpause it
+    Guardian,
+    // May change protocol and risk parameters
+    RiskManager,
+    // May manage protocol fees and the treasury
+    Treasurer,
+}
+
+#[derive(CandidType, Deserialize)]
+struct RoleAssignments {
+    owner: Option<Principal>,
+    pending_owner: Option<Principal>,
+    members: Vec<(Role, Vec<Principal>)>,
+}
+
+// Admin changes that go through the timelock
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+enum AdminAction {
+    UpdateParams(ProtocolParamsUpdate),
+    SetFarmingEmissionSchedule(EmissionSchedule),
+    SetRewardToken { stream: RewardStream, token: Option<RewardTokenConfig> },
+    GrantRole { principal: Principal, role: Role },
+    RevokeRole { principal: Principal, role: Role },
+    TransferOwnership(Principal),
+    SetTimelockDelay(u64),
+    SetAssetLimits { ledger: Principal, limits: AssetLimits },
+    SetRateLimit { category: RateLimitCategory, policy: RateLimitPolicy },
+    SetSafeModeThreshold(u128),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum RateLimitCategory {
+    Registration,
+    // Calls that reach out to a ledger canister
+    Ledger,
+    Claim,
+}
+
+// Token bucket: up to `capacity` calls in a burst, refilled at `refill_per_minute`
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy)]
+struct RateLimitConfig {
+    capacity: u32,
+    refill_per_minute: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy)]
+struct RateLimitPolicy {
+    per_principal: RateLimitConfig,
+    global: RateLimitConfig,
+}
+
+impl RateLimitCategory {
+    fn default_policy(self) -> RateLimitPolicy {
+        let config = |capacity, refill_per_minute| RateLimitConfig { capacity, refill_per_minute };
+        match self {
+            RateLimitCategory::Registration => RateLimitPolicy { per_principal: config(3, 1), global: config(100, 60) },
+            RateLimitCategory::Ledger => RateLimitPolicy { per_principal: config(10, 10), global: config(1_000, 600) },
+            RateLimitCategory::Claim => RateLimitPolicy { per_principal: config(5, 2), global: config(500, 300) },
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct TokenBucket {
+    tokens: u32,
+    last_refill: u64,
+}
+
+impl TokenBucket {
+    fn full(config: &RateLimitConfig, now: u64) -> Self {
+        TokenBucket { tokens: config.capacity, last_refill: now }
+    }
+
+    fn refill(&mut self, config: &RateLimitConfig, now: u64) {
+        if config.refill_per_minute == 0 {
+            return;
+        }
+        let interval = 60_000_000_000 / config.refill_per_minute as u64;
+        let earned = now.saturating_sub(self.last_refill) / interval;
+        if self.tokens as u64 + earned >= config.capacity as u64 {
+            self.tokens = config.capacity;
+            self.last_refill = now;
+        } else {
+            self.tokens += earned as u32;
+            self.last_refill += earned * interval;
+        }
+    }
+
+    // Seconds until the next token, for an empty bucket
+    fn retry_after_secs(&self, config: &RateLimitConfig, now: u64) -> u64 {
+        if config.refill_per_minute == 0 {
+            return u64::MAX;
+        }
+        let interval = 60_000_000_000 / config.refill_per_minute as u64;
+        let next_token = self.last_refill + interval;
+        next_token.saturating_sub(now).div_ceil(1_000_000_000)
+    }
+}
+
+#[derive(Default)]
+struct RateLimiter {
+    policies: HashMap<RateLimitCategory, RateLimitPolicy>,
+    principal_buckets: HashMap<(RateLimitCategory, Principal), TokenBucket>,
+    global_buckets: HashMap<RateLimitCategory, TokenBucket>,
+}
+
+// Risk limits for one asset; caps left as `None` are unlimited
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct AssetLimits {
+    supply_cap: Option<u64>,
+    borrow_cap: Option<u64>,
+    max_borrow_per_user: Option<u64>,
+    min_position_size: u64,
+}
+
+impl Default for AssetLimits {
+    fn default() -> Self {
+        AssetLimits {
+            supply_cap: None,
+            borrow_cap: None,
+            max_borrow_per_user: None,
+            min_position_size: DEFAULT_MIN_POSITION_SIZE,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum LimitError {
+    BelowMinimumPosition { minimum: u64, requested: u64 },
+    SupplyCapExceeded { cap: u64, current: u64, requested: u64 },
+    BorrowCapExceeded { cap: u64, current: u64, requested: u64 },
+    UserBorrowLimitExceeded { limit: u64, current: u64, requested: u64 },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let btc = |sats: &u64| (*sats as f64) / 100_000_000.0;
+        match self {
+            LimitError::BelowMinimumPosition { minimum, requested } => 
+                write!(f, "Amount {:.8} ckBTC is below the minimum position size of {:.8} ckBTC", btc(requested), btc(minimum)),
+            LimitError::SupplyCapExceeded { cap, current, requested } => 
+                write!(f, "Supply cap reached. Cap: {:.8} ckBTC, supplied: {:.8} ckBTC, requested: {:.8} ckBTC", btc(cap), btc(current), btc(requested)),
+            LimitError::BorrowCapExceeded { cap, current, requested } => 
+                write!(f, "Borrow cap reached. Cap: {:.8} ckBTC, borrowed: {:.8} ckBTC, requested: {:.8} ckBTC", btc(cap), btc(current), btc(requested)),
+            LimitError::UserBorrowLimitExceeded { limit, current, requested } => 
+                write!(f, "Per-user borrow limit reached. Limit: {:.8} ckBTC, your debt: {:.8} ckBTC, requested: {:.8} ckBTC", btc(limit), btc(current), btc(requested)),
+        }
+    }
+}
+
+// Why an update call was turned away before it ran; calls that do run still report their outcome as text
+#[derive(CandidType, Deserialize, Debug)]
+enum CallError {
+    // retry_after is in seconds
+    RateLimited { category: RateLimitCategory, retry_after: u64 },
+    Limit(LimitError),
+    Rejected(String),
+}
+
+impl From<String> for CallError {
+    fn from(message: String) -> Self {
+        CallError::Rejected(message)
+    }
+}
+
+impl From<LimitError> for CallError {
+    fn from(error: LimitError) -> Self {
+        CallError::Limit(error)
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct PendingChange {
+    id: u64,
+    action: AdminAction,
+    proposer: Principal,
+    queued_at: u64,
+    eta: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+enum MultisigAction {
+    Admin(AdminAction),
+    Pause,
+    Unpause,
+    SetOperationPaused { operation: Operation, paused: bool },
+    SetSigners { signers: Vec<Principal>, threshold: u32 },
+    CancelChange(u64),
+    WithdrawTreasury { to: Principal, amount: f64, ledger: Option<Principal> },
+    FundInsuranceFromTreasury(f64),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+enum ProposalStatus {
+    Pending,
+    Executed,
+    Failed(String),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct MultisigProposal {
+    id: u64,
+    action: MultisigAction,
+    proposer: Principal,
+    approvals: Vec<Principal>,
+    created_at: u64,
+    status: ProposalStatus,
+}
+
+// Multisig is disabled while `signers` is empty
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct MultisigConfig {
+    signers: Vec<Principal>,
+    threshold: u32,
+}
+
+// Actions stakers can vote on; passed proposals go through the same timelock as admin changes.
+// A pause takes effect at once since it only protects funds; unpausing stays with the owner or multisig.
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+enum GovernanceAction {
+    UpdateParams(ProtocolParamsUpdate),
+    Pause,
+    SetRewardToken { stream: RewardStream, token: Option<RewardTokenConfig> },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+enum GovernanceStatus {
+    Open,
+    Rejected,
+    // Passed and handed to the timelock (or applied, for pause/unpause)
+    Executed,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct GovernanceProposal {
+    id: u64,
+    proposer: Principal,
+    description: String,
+    action: GovernanceAction,
+    created_at: u64,
+    voting_ends_at: u64,
+    // Voting power of all stakers when the proposal was created; quorum is measured against it
+    total_voting_power: u64,
+    yes_votes: u64,
+    no_votes: u64,
+    voters: Vec<Principal>,
+    status: GovernanceStatus,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Operation {
+    Deposit,
+    Withdraw,
+    Borrow,
+    Repay,
+    Stake,
+    Lend,
+    Farm,
+    Liquidate,
+}
+
+const ALL_OPERATIONS: [Operation; 8] = [
+    Operation::Deposit,
+    Operation::Withdraw,
+    Operation::Borrow,
+    Operation::Repay,
+    Operation::Stake,
+    Operation::Lend,
+    Operation::Farm,
+    Operation::Liquidate,
+];
+
+#[derive(CandidType, Deserialize)]
+struct OperationStatus {
+    operation: Operation,
+    paused: bool,
+    allowed: bool,
+}
+
+#[derive(CandidType, Deserialize)]
+struct PauseFlags {
+    global_paused: bool,
+    safe_mode: bool,
+    operations: Vec<OperationStatus>,
+}
+
+// Protocol-owned funds per ledger, fed by the reserve factor and farming fees
+#[derive(Default)]
+struct Treasury {
+    balances: HashMap<Principal, u64>,
+    total_interest_reserves: u64,
+    total_farming_fees: u64,
+    total_withdrawn: HashMap<Principal, u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TreasuryInfo {
+    balances: Vec<(Principal, u64)>,
+    total_interest_reserves: u64,
+    total_farming_fees: u64,
+    total_withdrawn: Vec<(Principal, u64)>,
+    reserve_factor: f64,
+    farming_fee_rate: f64,
+}
+
+// ckBTC set aside to absorb bad debt before lenders take a loss
+#[derive(Default)]
+struct InsuranceFund {
+    balance: u64,
+    total_from_reserves: u64,
+    total_deposited: u64,
+    total_covered: u64,
+    contributors: HashMap<Principal, u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct InsuranceFundInfo {
+    balance: u64,
+    total_from_reserves: u64,
+    total_deposited: u64,
+    total_covered: u64,
+    supply_index: f64,
+}
+
+// Audit record of a liquidation that left debt uncovered, and how the shortfall was absorbed
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+struct BadDebtEvent {
+    id: u64,
+    borrower: Principal,
+    liquidator: Principal,
+    timestamp: u64,
+    debt: u64,
+    collateral_seized: u64,
+    shortfall: u64,
+    covered_by_insurance: u64,
+    socialized: u64,
+    unrecovered: u64,
+    supply_index: f64,
+}
+
+// Cycles balance sampled by a timer, net of top-ups received since the previous sample
+#[derive(CandidType, Deserialize, Clone, Copy)]
+struct CyclesSample {
+    timestamp: u64,
+    balance: u128,
+    received: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct CyclesTopUp {
+    contributor: Principal,
+    amount: u128,
+    timestamp: u64,
+}
+
+#[derive(Default)]
+struct CyclesMonitor {
+    samples: VecDeque<CyclesSample>,
+    received_since_sample: u128,
+    safe_mode_threshold: u128,
+    // When the canister last dropped below the threshold, cleared once it recovers
+    safe_mode_since: Option<u64>,
+    total_received: u128,
+    contributors: HashMap<Principal, u128>,
+    top_ups: VecDeque<CyclesTopUp>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CyclesStatus {
+    balance: u128,
+    safe_mode_threshold: u128,
+    safe_mode: bool,
+    safe_mode_since: Option<u64>,
+    burn_rate_per_day: Option<u128>,
+    estimated_days_remaining: Option<f64>,
+    last_sample: Option<u64>,
+    total_received: u128,
+}
+
+// Counters exported on `/metrics`; gauges are computed at scrape time
+#[derive(Default)]
+struct Metrics {
+    operation_requests: HashMap<Operation, u64>,
+    // Keyed by (ledger method, error variant)
+    ledger_errors: BTreeMap<(&'static str, &'static str), u64>,
+}
+
+// What one user contributes to the platform totals
+#[derive(Default, Clone, Copy)]
+struct UserFootprint {
+    balance: u128,
+    loans: u128,
+    staked: u128,
+    locked: u128,
+    lent: u128,
+    farmed: u128,
+    unbonding: u128,
+}
+
+impl UserFootprint {
+    fn of(data: &UserData) -> Self {
+        UserFootprint {
+            balance: data.ckbtc_balance as u128,
+            loans: data.loans as u128,
+            staked: data.staked as u128,
+            locked: locked_stake(data) as u128,
+            lent: data.lent as u128,
+            farmed: data.farmed as u128,
+            unbonding: data.pending_unbonds.iter().map(|entry| entry.amount as u128).sum(),
+        }
+    }
+
+    fn supplied(&self) -> u128 {
+        self.balance + self.staked + self.locked + self.lent + self.farmed
+    }
+}
+
+// Platform totals kept up to date by diffing each user's cached footprint
+#[derive(Default)]
+struct StatsState {
+    totals: UserFootprint,
+    active_borrowers: u64,
+    active_stakers: u64,
+    active_lenders: u64,
+    active_farmers: u64,
+    // Ordered by principal, so it doubles as the stable index for paginated listings
+    footprints: BTreeMap<Principal, UserFootprint>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct PlatformStats {
+    network: String,
+    total_users: u64,
+    active_borrowers: u64,
+    active_stakers: u64,
+    active_lenders: u64,
+    active_farmers: u64,
+    total_deposits: Nat,
+    total_loans: Nat,
+    total_staked: Nat,
+    total_locked_stake: Nat,
+    total_lent: Nat,
+    total_farmed: Nat,
+    total_unbonding: Nat,
+    tvl: Nat,
+    utilization: f64,
+    staking_apr: f64,
+    lending_apr: f64,
+    borrow_apr: f64,
+    farming_apr: f64,
+    paused: bool,
+}
+
+// Merkle tree over per-user balances and aggregate stats; its root hash is the canister's certified data
+struct CertifiedTree(RbTree<Vec<u8>, Hash>);
+
+impl Default for CertifiedTree {
+    fn default() -> Self {
+        CertifiedTree(RbTree::new())
+    }
+}
+
+const CERTIFIED_STATS_KEY: &[u8] = b"stats";
+
+// Balances as certified; the leaf for a user is the SHA-256 of this record's Candid encoding
+#[derive(CandidType, Deserialize, Clone)]
+struct UserBalances {
+    ckbtc_balance: u64,
+    loans: u64,
+    staked: u64,
+    locked_stake: u64,
+    lent: u64,
+    farmed: u64,
+    unbonding: u64,
+}
+
+// The time-independent part of `PlatformStats`, which is what can be certified
+#[derive(CandidType, Deserialize, Clone)]
+struct CertifiedStats {
+    total_users: u64,
+    active_borrowers: u64,
+    active_stakers: u64,
+    active_lenders: u64,
+    active_farmers: u64,
+    total_deposits: Nat,
+    total_loans: Nat,
+    total_staked: Nat,
+    total_locked_stake: Nat,
+    total_lent: Nat,
+    total_farmed: Nat,
+    total_unbonding: Nat,
+}
+
+// `certificate` is the system certificate over the tree's root hash; `witness` is the CBOR-encoded
+// hash tree proving the record's leaf. The certificate is only available in non-replicated queries.
+#[derive(CandidType, Deserialize)]
+struct CertifiedUserBalances {
+    balances: Option<UserBalances>,
+    #[serde(with = "serde_bytes")]
+    certificate: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    witness: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CertifiedPlatformStats {
+    stats: CertifiedStats,
+    #[serde(with = "serde_bytes")]
+    certificate: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    witness: Vec<u8>,
+}
+
+// Filters for `list_users`; every filter that is set must match
+#[derive(CandidType, Deserialize, Default)]
+struct UserFilter {
+    has_loans: Option<bool>,
+    // Only users whose health factor is below this value; users without loans never match
+    health_factor_below: Option<f64>,
+    min_balance: Option<u64>,
+}
+
+// Lets an admin fetch the CSV export over plain HTTP, where there is no caller to authenticate
+struct ExportToken {
+    issued_to: Principal,
+    expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ExportTokenInfo {
+    token: String,
+    expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct PositionsExport {
+    csv: String,
+    next_cursor: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct UserPage {
+    users: Vec<UserData>,
+    // Pass back as `cursor` to fetch the next page; `None` once the listing is complete
+    next_cursor: Option<Principal>,
 }
 
 // Global state
@@ -125,7 +990,42 @@ struct UserData {
 struct State {
     users: HashMap<Principal, UserData>,
     is_paused: bool,
-    admin: Option<Principal>,
+    paused_operations: HashSet<Operation>,
+    owner: Option<Principal>,
+    pending_owner: Option<Principal>,
+    roles: HashMap<Role, HashSet<Principal>>,
+    farming: FarmingPool,
+    reward_tokens: HashMap<RewardStream, RewardTokenConfig>,
+    // Tokens a stream used to pay in, kept so rewards accrued on them stay claimable
+    retired_reward_tokens: HashMap<Principal, RewardTokenConfig>,
+    next_stake_position_id: u64,
+    next_unbond_id: u64,
+    params: ProtocolParams,
+    timelock_delay_secs: u64,
+    next_change_id: u64,
+    pending_changes: BTreeMap<u64, PendingChange>,
+    change_timers: HashMap<u64, ic_cdk_timers::TimerId>,
+    multisig: MultisigConfig,
+    next_proposal_id: u64,
+    proposals: BTreeMap<u64, MultisigProposal>,
+    next_governance_proposal_id: u64,
+    governance_proposals: BTreeMap<u64, GovernanceProposal>,
+    // Per-proposal voting power taken at creation, so stake moved afterwards can't vote twice
+    voting_snapshots: HashMap<u64, HashMap<Principal, u64>>,
+    treasury: Treasury,
+    asset_limits: HashMap<Principal, AssetLimits>,
+    insurance: InsuranceFund,
+    // Audit record only: the share of lent principal that has survived socialized losses, starting at 1.0.
+    // Lender balances are written down directly, so nothing is scaled by it.
+    supply_index: f64,
+    next_bad_debt_id: u64,
+    bad_debt_events: Vec<BadDebtEvent>,
+    metrics: Metrics,
+    stats: StatsState,
+    certified: CertifiedTree,
+    rate_limiter: RateLimiter,
+    cycles: CyclesMonitor,
+    export_tokens: HashMap<String, ExportToken>,
 }
 
 static mut STATE: Option<State> = None;
@@ -139,107 +1039,2028 @@ fn state() -> &'static mut State {
     }
 }
 
+fn params() -> &'static ProtocolParams {
+    &state().params
+}
+
 // Initialization
 #[init]
 fn init() {
-    let s = state();
-    s.admin = Some(caller());
+    initialize();
     ic_cdk::println!("DeFi backend initialized on {}", if IS_TESTNET { "testnet" } else { "mainnet" });
 }
 
-// Admin functions
-#[update]
-fn pause_contract() -> String {
-    let s = state();
-    if Some(caller()) != s.admin {
-        return "Unauthorized: Only admin can pause".to_string();
+// State lives on the heap, so an upgrade starts from scratch: restore the defaults and restart the timers
+#[post_upgrade]
+fn post_upgrade() {
+    initialize();
+    ic_cdk::println!("DeFi backend upgraded on {}", if IS_TESTNET { "testnet" } else { "mainnet" });
+}
+
+fn initialize() {
+    let s = state();
+    s.owner = Some(caller());
+    let now = ic_cdk::api::time();
+    s.farming.schedule = Some(EmissionSchedule::Halving {
+        start_time: now,
+        initial_reward_per_second: DEFAULT_FARMING_REWARD_PER_SECOND,
+        halving_interval_secs: DEFAULT_FARMING_HALVING_INTERVAL_SECS,
+    });
+    s.farming.last_update = now;
+    s.timelock_delay_secs = DEFAULT_TIMELOCK_DELAY_SECS;
+    s.supply_index = 1.0;
+    s.cycles.safe_mode_threshold = DEFAULT_SAFE_MODE_THRESHOLD_CYCLES;
+    certify_stats();
+    record_cycles_sample();
+    start_timers();
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(AUTO_COMPOUND_INTERVAL_SECS), auto_compound_all);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(RATE_LIMIT_PRUNE_INTERVAL_SECS), prune_rate_limit_buckets);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(CYCLES_SAMPLE_INTERVAL_SECS), record_cycles_sample);
+}
+
+// Access control
+fn has_role(principal: &Principal, role: Role) -> bool {
+    let s = state();
+    if s.owner.as_ref() == Some(principal) {
+        return true;
+    }
+    s.roles.get(&role).is_some_and(|members| members.contains(principal))
+}
+
+fn ensure_role(role: Role) -> Result<(), String> {
+    if has_role(&caller(), role) {
+        Ok(())
+    } else {
+        Err(format!("Unauthorized: {:?} role required", role))
+    }
+}
+
+// Any role holder counts as an admin for read access to other users' data
+fn is_admin(principal: &Principal) -> bool {
+    [Role::Guardian, Role::RiskManager, Role::Treasurer].into_iter().any(|role| has_role(principal, role))
+}
+
+fn ensure_admin() -> Result<(), String> {
+    if is_admin(&caller()) {
+        Ok(())
+    } else {
+        Err("Unauthorized: admin role required".to_string())
+    }
+}
+
+#[update]
+fn grant_role(principal: Principal, role: Role) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    submit_admin_action(Role::Owner, AdminAction::GrantRole { principal, role })
+}
+
+#[update]
+fn revoke_role(principal: Principal, role: Role) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    submit_admin_action(Role::Owner, AdminAction::RevokeRole { principal, role })
+}
+
+// First step of an ownership transfer; once the timelock passes the new owner must call `accept_ownership`
+#[update]
+fn transfer_ownership(new_owner: Principal) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    submit_admin_action(Role::Owner, AdminAction::TransferOwnership(new_owner))
+}
+
+#[update]
+fn accept_ownership() -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    let s = state();
+    let user = caller();
+    if s.pending_owner != Some(user) {
+        return "Unauthorized: no pending ownership transfer for caller".to_string();
+    }
+    s.owner = Some(user);
+    s.pending_owner = None;
+    format!("{} is now the owner", user)
+}
+
+#[query]
+fn list_roles() -> RoleAssignments {
+    let s = state();
+    RoleAssignments {
+        owner: s.owner,
+        pending_owner: s.pending_owner,
+        members: [Role::Guardian, Role::RiskManager, Role::Treasurer]
+            .into_iter()
+            .map(|role| (role, s.roles.get(&role).map_or(Vec::new(), |members| members.iter().copied().collect())))
+            .collect(),
+    }
+}
+
+// Admin functions
+#[update]
+fn pause_contract() -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    let s = state();
+    if let Err(e) = ensure_role(Role::Guardian) {
+        return e;
+    }
+    s.is_paused = true;
+    "Contract paused".to_string()
+}
+
+#[update]
+fn unpause_contract() -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    let s = state();
+    if let Err(e) = ensure_role(Role::Owner) {
+        return e;
+    }
+    if let Err(e) = ensure_multisig_disabled() {
+        return e;
+    }
+    s.is_paused = false;
+    "Contract unpaused".to_string()
+}
+
+#[update]
+fn set_farming_emission_schedule(schedule: EmissionSchedule) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    submit_admin_action(Role::RiskManager, AdminAction::SetFarmingEmissionSchedule(schedule))
+}
+
+fn validate_emission_schedule(schedule: &EmissionSchedule) -> Result<(), String> {
+    match schedule {
+        EmissionSchedule::Halving { halving_interval_secs, .. } => {
+            if *halving_interval_secs == 0 {
+                return Err("Halving interval must be greater than 0".to_string());
+            }
+        }
+        EmissionSchedule::Curve(phases) => {
+            if phases.is_empty() {
+                return Err("Emission curve must contain at least one phase".to_string());
+            }
+            if phases.windows(2).any(|w| w[0].start_time >= w[1].start_time) {
+                return Err("Emission phases must be sorted by strictly increasing start time".to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply_emission_schedule(schedule: EmissionSchedule) -> Result<String, String> {
+    validate_emission_schedule(&schedule)?;
+    let s = state();
+    // Checkpoint emissions under the old schedule before switching
+    update_farming_pool(&mut s.farming, ic_cdk::api::time());
+    s.farming.schedule = Some(schedule);
+    Ok("Farming emission schedule updated".to_string())
+}
+
+#[query]
+fn get_params() -> ProtocolParams {
+    params().clone()
+}
+
+#[update]
+fn update_params(update: ProtocolParamsUpdate) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    submit_admin_action(Role::RiskManager, AdminAction::UpdateParams(update))
+}
+
+fn merge_params(current: &ProtocolParams, update: &ProtocolParamsUpdate) -> ProtocolParams {
+    ProtocolParams {
+        staking_rate: update.staking_rate.unwrap_or(current.staking_rate),
+        borrow_rate: update.borrow_rate.unwrap_or(current.borrow_rate),
+        lending_rate: update.lending_rate.unwrap_or(current.lending_rate),
+        collateral_ratio: update.collateral_ratio.unwrap_or(current.collateral_ratio),
+        early_exit_penalty_rate: update.early_exit_penalty_rate.unwrap_or(current.early_exit_penalty_rate),
+        unbonding_delay_secs: update.unbonding_delay_secs.unwrap_or(current.unbonding_delay_secs),
+        reserve_factor: update.reserve_factor.unwrap_or(current.reserve_factor),
+        farming_fee_rate: update.farming_fee_rate.unwrap_or(current.farming_fee_rate),
+        insurance_reserve_share: update.insurance_reserve_share.unwrap_or(current.insurance_reserve_share),
+        liquidation_bonus: update.liquidation_bonus.unwrap_or(current.liquidation_bonus),
+    }
+}
+
+fn validate_params(p: &ProtocolParams) -> Result<(), String> {
+    let in_range = |name: &str, value: f64, min: f64, max: f64| {
+        if value.is_finite() && value >= min && value <= max {
+            Ok(())
+        } else {
+            Err(format!("{} must be between {} and {}, got {}", name, min, max, value))
+        }
+    };
+    in_range("staking_rate", p.staking_rate, 0.0, MAX_STAKING_RATE)?;
+    in_range("lending_rate", p.lending_rate, 0.0, MAX_LENDING_RATE)?;
+    in_range("borrow_rate", p.borrow_rate, 0.0, MAX_BORROW_RATE)?;
+    in_range("collateral_ratio", p.collateral_ratio, MIN_COLLATERAL_RATIO, MAX_COLLATERAL_RATIO)?;
+    in_range("early_exit_penalty_rate", p.early_exit_penalty_rate, 0.0, MAX_EARLY_EXIT_PENALTY_RATE)?;
+    in_range("reserve_factor", p.reserve_factor, 0.0, MAX_RESERVE_FACTOR)?;
+    in_range("farming_fee_rate", p.farming_fee_rate, 0.0, MAX_FARMING_FEE_RATE)?;
+    in_range("insurance_reserve_share", p.insurance_reserve_share, 0.0, 1.0)?;
+    in_range("liquidation_bonus", p.liquidation_bonus, 0.0, MAX_LIQUIDATION_BONUS)?;
+    if p.unbonding_delay_secs > MAX_UNBONDING_DELAY_SECS {
+        return Err(format!("unbonding_delay_secs must be at most {}, got {}", MAX_UNBONDING_DELAY_SECS, p.unbonding_delay_secs));
+    }
+    if p.lending_rate > p.borrow_rate * (1.0 - p.reserve_factor) {
+        return Err("lending_rate must not exceed borrow_rate net of the reserve factor".to_string());
+    }
+    Ok(())
+}
+
+// Validates and applies a parameter update, checkpointing interest accrued under the old rates first
+fn apply_params_update(update: ProtocolParamsUpdate) -> Result<String, String> {
+    let s = state();
+    let current = s.params.clone();
+    let new = merge_params(&current, &update);
+    validate_params(&new)?;
+
+    let now = ic_cdk::api::time();
+    let mut streams = Vec::new();
+    if new.staking_rate != current.staking_rate {
+        streams.push(RewardStream::Staking);
+    }
+    if new.lending_rate != current.lending_rate {
+        streams.push(RewardStream::Lending);
+    }
+    for stream in streams {
+        let ledger = reward_token(stream).ledger;
+        for data in s.users.values_mut() {
+            let earned = take_stream_rewards(&mut s.farming, data, stream, now);
+            accrue_reward(data, ledger, earned);
+        }
+    }
+    if new.borrow_rate != current.borrow_rate {
+        // Capitalize interest owed so far so it is not repriced at the new rate
+        for data in s.users.values_mut() {
+            capitalize_loan_interest(data, current.borrow_rate, now);
+        }
+    }
+    if new.farming_fee_rate != current.farming_fee_rate {
+        update_farming_pool(&mut s.farming, now);
+    }
+    s.params = new;
+    refresh_all_user_stats();
+    Ok("Protocol parameters updated".to_string())
+}
+
+#[update]
+fn set_asset_limits(ledger: Principal, limits: AssetLimits) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    submit_admin_action(Role::RiskManager, AdminAction::SetAssetLimits { ledger, limits })
+}
+
+fn asset_limits(ledger: Principal) -> AssetLimits {
+    state().asset_limits.get(&ledger).cloned().unwrap_or_default()
+}
+
+#[query]
+fn get_asset_limits(ledger: Option<Principal>) -> AssetLimits {
+    asset_limits(ledger.unwrap_or(*CKBTC_CANISTER_ID))
+}
+
+fn total_supplied() -> u64 {
+    state().stats.totals.supplied() as u64
+}
+
+// Recorded loan balances; interest accrued since each loan's last checkpoint is not included
+fn total_borrowed() -> u64 {
+    state().stats.totals.loans as u64
+}
+
+// Checks a new ckBTC position against the configured caps before any funds move
+fn check_position_limits(operation: Operation, user: Principal, amount: u64) -> Result<(), LimitError> {
+    let limits = asset_limits(*CKBTC_CANISTER_ID);
+    if amount < limits.min_position_size {
+        return Err(LimitError::BelowMinimumPosition { minimum: limits.min_position_size, requested: amount });
+    }
+    match operation {
+        Operation::Deposit => {
+            if let Some(cap) = limits.supply_cap {
+                let current = total_supplied();
+                if current + amount > cap {
+                    return Err(LimitError::SupplyCapExceeded { cap, current, requested: amount });
+                }
+            }
+        }
+        Operation::Borrow => {
+            if let Some(cap) = limits.borrow_cap {
+                let current = total_borrowed();
+                if current + amount > cap {
+                    return Err(LimitError::BorrowCapExceeded { cap, current, requested: amount });
+                }
+            }
+            if let Some(limit) = limits.max_borrow_per_user {
+                let current = state().users.get(&user).map_or(0, loan_debt);
+                if current + amount > limit {
+                    return Err(LimitError::UserBorrowLimitExceeded { limit, current, requested: amount });
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Lets clients see which limit an operation would hit without attempting it
+#[query]
+fn check_limits(operation: Operation, amount: f64) -> Result<(), LimitError> {
+    check_position_limits(operation, caller(), ckbtc_to_sats(amount))
+}
+
+// Rate limiting
+#[update]
+fn set_rate_limit(category: RateLimitCategory, policy: RateLimitPolicy) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    submit_admin_action(Role::RiskManager, AdminAction::SetRateLimit { category, policy })
+}
+
+fn rate_limit_policy(category: RateLimitCategory) -> RateLimitPolicy {
+    state().rate_limiter.policies.get(&category).copied().unwrap_or_else(|| category.default_policy())
+}
+
+#[query]
+fn get_rate_limits() -> Vec<(RateLimitCategory, RateLimitPolicy)> {
+    [RateLimitCategory::Registration, RateLimitCategory::Ledger, RateLimitCategory::Claim]
+        .into_iter()
+        .map(|category| (category, rate_limit_policy(category)))
+        .collect()
+}
+
+// Takes a token from both the caller's bucket and the global one, or neither if either is empty.
+// With `consume` unset the buckets are only inspected.
+fn take_rate_limit_token(category: RateLimitCategory, consume: bool) -> Result<(), CallError> {
+    let limiter = &mut state().rate_limiter;
+    let policy = rate_limit_policy(category);
+    let now = ic_cdk::api::time();
+    let mut own = limiter.principal_buckets
+        .get(&(category, caller()))
+        .copied()
+        .unwrap_or_else(|| TokenBucket::full(&policy.per_principal, now));
+    let mut global = limiter.global_buckets
+        .get(&category)
+        .copied()
+        .unwrap_or_else(|| TokenBucket::full(&policy.global, now));
+    own.refill(&policy.per_principal, now);
+    global.refill(&policy.global, now);
+    if own.tokens == 0 {
+        let retry_after = own.retry_after_secs(&policy.per_principal, now);
+        return Err(CallError::RateLimited { category, retry_after });
+    }
+    if global.tokens == 0 {
+        let retry_after = global.retry_after_secs(&policy.global, now);
+        return Err(CallError::RateLimited { category, retry_after });
+    }
+    if consume {
+        own.tokens -= 1;
+        global.tokens -= 1;
+        limiter.principal_buckets.insert((category, caller()), own);
+        limiter.global_buckets.insert(category, global);
+    }
+    Ok(())
+}
+
+// Tells clients whether their next call in this category would be throttled, and for how long
+#[query]
+fn check_rate_limit(category: RateLimitCategory) -> Result<(), CallError> {
+    take_rate_limit_token(category, false)
+}
+
+fn ensure_rate_limit(category: RateLimitCategory) -> Result<(), CallError> {
+    take_rate_limit_token(category, true)
+}
+
+// A bucket that has refilled completely behaves exactly like a missing one, so it can be dropped
+fn prune_rate_limit_buckets() {
+    let now = ic_cdk::api::time();
+    let policies: HashMap<RateLimitCategory, RateLimitPolicy> = get_rate_limits().into_iter().collect();
+    state().rate_limiter.principal_buckets.retain(|(category, _), bucket| {
+        let config = &policies[category].per_principal;
+        bucket.refill(config, now);
+        bucket.tokens < config.capacity
+    });
+}
+
+// Cycles monitoring
+// Evaluated live, so the switch happens as soon as the balance crosses the threshold
+fn in_safe_mode() -> bool {
+    ic_cdk::api::canister_balance128() < state().cycles.safe_mode_threshold
+}
+
+fn update_safe_mode() {
+    let now = ic_cdk::api::time();
+    let safe_mode = in_safe_mode();
+    let cycles = &mut state().cycles;
+    match (safe_mode, cycles.safe_mode_since) {
+        (true, None) => {
+            cycles.safe_mode_since = Some(now);
+            ic_cdk::println!("Entering safe mode: cycles balance below {}", cycles.safe_mode_threshold);
+        }
+        (false, Some(_)) => {
+            cycles.safe_mode_since = None;
+            ic_cdk::println!("Leaving safe mode: cycles balance restored");
+        }
+        _ => {}
+    }
+}
+
+fn record_cycles_sample() {
+    let cycles = &mut state().cycles;
+    cycles.samples.push_back(CyclesSample {
+        timestamp: ic_cdk::api::time(),
+        balance: ic_cdk::api::canister_balance128(),
+        received: std::mem::take(&mut cycles.received_since_sample),
+    });
+    if cycles.samples.len() > MAX_CYCLES_SAMPLES {
+        cycles.samples.pop_front();
+    }
+    update_safe_mode();
+}
+
+// Cycles spent across the sample window, with top-ups added back so they don't mask the burn
+fn cycles_burn_rate_per_day() -> Option<u128> {
+    let samples = &state().cycles.samples;
+    let (first, last) = (samples.front()?, samples.back()?);
+    let elapsed_secs = (last.timestamp - first.timestamp) / 1_000_000_000;
+    if elapsed_secs == 0 {
+        return None;
+    }
+    let burned: u128 = samples
+        .iter()
+        .zip(samples.iter().skip(1))
+        .map(|(prev, next)| (prev.balance + next.received).saturating_sub(next.balance))
+        .sum();
+    Some(burned * 86_400 / elapsed_secs as u128)
+}
+
+#[query]
+fn get_cycles_status() -> CyclesStatus {
+    let cycles = &state().cycles;
+    let balance = ic_cdk::api::canister_balance128();
+    let burn_rate_per_day = cycles_burn_rate_per_day();
+    CyclesStatus {
+        balance,
+        safe_mode_threshold: cycles.safe_mode_threshold,
+        safe_mode: in_safe_mode(),
+        safe_mode_since: cycles.safe_mode_since,
+        burn_rate_per_day,
+        estimated_days_remaining: burn_rate_per_day.filter(|rate| *rate > 0).map(|rate| balance as f64 / rate as f64),
+        last_sample: cycles.samples.back().map(|sample| sample.timestamp),
+        total_received: cycles.total_received,
+    }
+}
+
+#[query]
+fn get_cycles_history() -> Vec<CyclesSample> {
+    state().cycles.samples.iter().copied().collect()
+}
+
+#[query]
+fn get_cycles_contributors() -> Vec<(Principal, u128)> {
+    let mut contributors: Vec<(Principal, u128)> = state().cycles.contributors.iter().map(|(p, amount)| (*p, *amount)).collect();
+    contributors.sort_by_key(|(_, amount)| std::cmp::Reverse(*amount));
+    contributors
+}
+
+#[query]
+fn get_cycles_top_ups() -> Vec<CyclesTopUp> {
+    state().cycles.top_ups.iter().rev().cloned().collect()
+}
+
+// Accepts every cycle attached to the call; anyone (usually a cycles wallet) may top up
+#[update]
+fn accept_cycles() -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    let amount = ic_cdk::api::call::msg_cycles_accept128(ic_cdk::api::call::msg_cycles_available128());
+    if amount == 0 {
+        return "No cycles attached".to_string();
+    }
+    let contributor = caller();
+    let cycles = &mut state().cycles;
+    cycles.received_since_sample += amount;
+    cycles.total_received += amount;
+    *cycles.contributors.entry(contributor).or_insert(0) += amount;
+    cycles.top_ups.push_back(CyclesTopUp { contributor, amount, timestamp: ic_cdk::api::time() });
+    if cycles.top_ups.len() > MAX_CYCLES_TOP_UPS {
+        cycles.top_ups.pop_front();
+    }
+    update_safe_mode();
+    format!("Accepted {} cycles. Balance: {} cycles", amount, ic_cdk::api::canister_balance128())
+}
+
+#[update]
+fn set_safe_mode_threshold(threshold: u128) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    submit_admin_action(Role::RiskManager, AdminAction::SetSafeModeThreshold(threshold))
+}
+
+// Passing `None` resets the stream to paying rewards in ckBTC
+#[update]
+fn set_reward_token(stream: RewardStream, token: Option<RewardTokenConfig>) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    submit_admin_action(Role::RiskManager, AdminAction::SetRewardToken { stream, token })
+}
+
+fn apply_reward_token(stream: RewardStream, token: Option<RewardTokenConfig>) -> String {
+    let s = state();
+    // Checkpoint everything earned so far so it stays payable in the old token
+    let old_token = reward_token(stream);
+    let old_ledger = old_token.ledger;
+    let now = ic_cdk::api::time();
+    update_farming_pool(&mut s.farming, now);
+    sweep_farming_fees();
+    for data in s.users.values_mut() {
+        let earned = take_stream_rewards(&mut s.farming, data, stream, now);
+        accrue_reward(data, old_ledger, earned);
+    }
+    s.retired_reward_tokens.insert(old_ledger, old_token);
+    match token {
+        Some(token) => {
+            let message = format!("{:?} rewards are now paid in {} ({})", stream, token.symbol, token.ledger);
+            s.reward_tokens.insert(stream, token);
+            message
+        }
+        None => {
+            s.reward_tokens.remove(&stream);
+            format!("{:?} rewards are now paid in ckBTC", stream)
+        }
+    }
+}
+
+// Treasury
+fn credit_treasury(ledger: Principal, amount: u64) {
+    *state().treasury.balances.entry(ledger).or_insert(0) += amount;
+}
+
+fn credit_interest_reserves(interest_paid: u64) {
+    let s = state();
+    let reserves = (interest_paid as f64 * s.params.reserve_factor) as u64;
+    if reserves > 0 {
+        let to_insurance = (reserves as f64 * s.params.insurance_reserve_share) as u64;
+        s.insurance.balance += to_insurance;
+        s.insurance.total_from_reserves += to_insurance;
+        credit_treasury(*CKBTC_CANISTER_ID, reserves - to_insurance);
+        s.treasury.total_interest_reserves += reserves;
+    }
+}
+
+// Farming fees are booked in whichever token the farming stream currently pays
+fn sweep_farming_fees() {
+    let fees = std::mem::take(&mut state().farming.accrued_fees);
+    if fees > 0 {
+        credit_treasury(reward_token(RewardStream::Farming).ledger, fees);
+        state().treasury.total_farming_fees += fees;
+    }
+}
+
+#[query]
+fn get_treasury_info() -> TreasuryInfo {
+    let s = state();
+    let mut pool = s.farming.clone();
+    update_farming_pool(&mut pool, ic_cdk::api::time());
+    let mut balances = s.treasury.balances.clone();
+    if pool.accrued_fees > 0 {
+        *balances.entry(reward_token(RewardStream::Farming).ledger).or_insert(0) += pool.accrued_fees;
+    }
+    TreasuryInfo {
+        balances: balances.into_iter().collect(),
+        total_interest_reserves: s.treasury.total_interest_reserves,
+        total_farming_fees: s.treasury.total_farming_fees + pool.accrued_fees,
+        total_withdrawn: s.treasury.total_withdrawn.iter().map(|(ledger, amount)| (*ledger, *amount)).collect(),
+        reserve_factor: s.params.reserve_factor,
+        farming_fee_rate: s.params.farming_fee_rate,
+    }
+}
+
+// `ledger` defaults to ckBTC; the ledger fee is paid out of the withdrawn amount
+#[update]
+async fn withdraw_treasury(to: Principal, amount: f64, ledger: Option<Principal>) -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Ledger)?;
+    Ok(process_treasury_withdrawal(to, amount, ledger).await)
+}
+
+async fn process_treasury_withdrawal(to: Principal, amount: f64, ledger: Option<Principal>) -> String {
+    if let Err(e) = ensure_role(Role::Treasurer) {
+        return e;
+    }
+    if let Err(e) = ensure_multisig_disabled() {
+        return e;
+    }
+    execute_treasury_withdrawal(to, amount, ledger).await.unwrap_or_else(|e| e)
+}
+
+async fn execute_treasury_withdrawal(to: Principal, amount: f64, ledger: Option<Principal>) -> Result<String, String> {
+    if amount <= 0.0 {
+        return Err("Amount must be greater than 0".to_string());
+    }
+    let s = state();
+    update_farming_pool(&mut s.farming, ic_cdk::api::time());
+    sweep_farming_fees();
+    let ledger = ledger.unwrap_or(*CKBTC_CANISTER_ID);
+    let fee = if ledger == *CKBTC_CANISTER_ID {
+        CKBTC_TRANSFER_FEE
+    } else {
+        match reward_token_for_ledger(ledger) {
+            Some(token) => token.fee,
+            None => return Err(format!("Unknown treasury ledger {}", ledger)),
+        }
+    };
+    let units = ckbtc_to_sats(amount);
+    let available = s.treasury.balances.get(&ledger).copied().unwrap_or(0);
+    if units > available {
+        return Err(format!("Insufficient treasury balance. Available: {:.8}", (available as f64)/100_000_000.0));
+    }
+    if units <= fee {
+        return Err(format!("Amount must exceed the ledger fee of {:.8}", (fee as f64)/100_000_000.0));
+    }
+    // Reserve before the transfer so concurrent withdrawals can't overdraw
+    *s.treasury.balances.entry(ledger).or_insert(0) -= units;
+    match transfer_from_canister(ledger, fee, to, units - fee).await {
+        Ok(tx_id) => {
+            *s.treasury.total_withdrawn.entry(ledger).or_insert(0) += units;
+            Ok(format!("Withdrew {:.8} from the treasury to {}. Transaction ID: {}", amount, to, tx_id))
+        }
+        Err(e) => {
+            credit_treasury(ledger, units);
+            Err(format!("Treasury withdrawal failed: {}", e))
+        }
+    }
+}
+
+// Timelocked admin changes
+#[update]
+fn set_timelock_delay(delay_secs: u64) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    submit_admin_action(Role::Owner, AdminAction::SetTimelockDelay(delay_secs))
+}
+
+// Entry point for role holders acting directly; once multisig is configured these go through `propose_action`
+fn submit_admin_action(role: Role, action: AdminAction) -> String {
+    if let Err(e) = ensure_role(role) {
+        return e;
+    }
+    if let Err(e) = ensure_multisig_disabled() {
+        return e;
+    }
+    if let Err(e) = validate_admin_action(&action) {
+        return e;
+    }
+    queue_admin_action(action)
+}
+
+fn validate_admin_action(action: &AdminAction) -> Result<(), String> {
+    match action {
+        AdminAction::UpdateParams(update) => validate_params(&merge_params(params(), update)),
+        AdminAction::SetFarmingEmissionSchedule(schedule) => validate_emission_schedule(schedule),
+        AdminAction::GrantRole { role: Role::Owner, .. } | AdminAction::RevokeRole { role: Role::Owner, .. } => {
+            Err("Ownership cannot be granted or revoked; use `transfer_ownership` instead".to_string())
+        }
+        AdminAction::SetTimelockDelay(delay_secs) if *delay_secs > MAX_TIMELOCK_DELAY_SECS => {
+            Err(format!("Timelock delay must be at most {} seconds", MAX_TIMELOCK_DELAY_SECS))
+        }
+        AdminAction::SetAssetLimits { limits, .. } if limits.min_position_size <= CKBTC_TRANSFER_FEE => {
+            Err(format!("min_position_size must exceed the transfer fee of {} sats", CKBTC_TRANSFER_FEE))
+        }
+        AdminAction::SetRateLimit { policy, .. } if policy.per_principal.capacity == 0 || policy.global.capacity == 0 => {
+            Err("Rate limit capacity must be at least 1; pause the operation instead to block it".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Single code path that actually performs an admin change once it is allowed to run
+fn execute_admin_action(action: AdminAction) -> Result<String, String> {
+    let s = state();
+    match action {
+        AdminAction::UpdateParams(update) => apply_params_update(update),
+        AdminAction::SetFarmingEmissionSchedule(schedule) => apply_emission_schedule(schedule),
+        AdminAction::SetRewardToken { stream, token } => Ok(apply_reward_token(stream, token)),
+        AdminAction::GrantRole { principal, role } => {
+            if s.roles.entry(role).or_default().insert(principal) {
+                Ok(format!("Granted {:?} to {}", role, principal))
+            } else {
+                Err(format!("{} already has {:?}", principal, role))
+            }
+        }
+        AdminAction::RevokeRole { principal, role } => {
+            if s.roles.get_mut(&role).is_some_and(|members| members.remove(&principal)) {
+                Ok(format!("Revoked {:?} from {}", role, principal))
+            } else {
+                Err(format!("{} does not have {:?}", principal, role))
+            }
+        }
+        AdminAction::TransferOwnership(new_owner) => {
+            s.pending_owner = Some(new_owner);
+            Ok(format!("Ownership transfer to {} initiated. It takes effect once they call `accept_ownership`.", new_owner))
+        }
+        AdminAction::SetTimelockDelay(delay_secs) => {
+            s.timelock_delay_secs = delay_secs;
+            Ok(format!("Timelock delay set to {} seconds", delay_secs))
+        }
+        AdminAction::SetAssetLimits { ledger, limits } => {
+            s.asset_limits.insert(ledger, limits);
+            Ok(format!("Updated risk limits for {}", ledger))
+        }
+        AdminAction::SetRateLimit { category, policy } => {
+            s.rate_limiter.policies.insert(category, policy);
+            Ok(format!("Updated {:?} rate limits", category))
+        }
+        AdminAction::SetSafeModeThreshold(threshold) => {
+            s.cycles.safe_mode_threshold = threshold;
+            update_safe_mode();
+            Ok(format!("Safe mode threshold set to {} cycles", threshold))
+        }
+    }
+}
+
+fn queue_admin_action(action: AdminAction) -> String {
+    let s = state();
+    let id = s.next_change_id;
+    s.next_change_id += 1;
+    let now = ic_cdk::api::time();
+    let delay_secs = s.timelock_delay_secs;
+    let timer = ic_cdk_timers::set_timer(Duration::from_secs(delay_secs), move || {
+        match run_pending_change(id) {
+            Ok(message) => ic_cdk::println!("Executed timelocked change {}: {}", id, message),
+            Err(e) => ic_cdk::println!("Timelocked change {} failed: {}", id, e),
+        }
+    });
+    s.change_timers.insert(id, timer);
+    s.pending_changes.insert(id, PendingChange {
+        id,
+        action,
+        proposer: caller(),
+        queued_at: now,
+        eta: now + delay_secs * 1_000_000_000,
+    });
+    format!("Change {} queued; it executes in {} seconds unless cancelled by the owner", id, delay_secs)
+}
+
+fn run_pending_change(id: u64) -> Result<String, String> {
+    let s = state();
+    match s.pending_changes.get(&id) {
+        Some(change) if change.eta > ic_cdk::api::time() => {
+            return Err(format!("Change {} is timelocked until {}", id, change.eta));
+        }
+        Some(_) => {}
+        None => return Err(format!("No pending change with id {}", id)),
+    }
+    let change = s.pending_changes.remove(&id).unwrap();
+    if let Some(timer) = s.change_timers.remove(&id) {
+        ic_cdk_timers::clear_timer(timer);
+    }
+    execute_admin_action(change.action)
+}
+
+// Fallback for a matured change whose timer callback trapped, e.g. when the canister was low on cycles
+#[update]
+fn execute_change(id: u64) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    match run_pending_change(id) {
+        Ok(message) => message,
+        Err(e) => e,
+    }
+}
+
+#[update]
+fn cancel_change(id: u64) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    if let Err(e) = ensure_role(Role::Owner) {
+        return e;
+    }
+    if let Err(e) = ensure_multisig_disabled() {
+        return e;
+    }
+    cancel_pending_change(id).unwrap_or_else(|e| e)
+}
+
+fn cancel_pending_change(id: u64) -> Result<String, String> {
+    let s = state();
+    match s.pending_changes.remove(&id) {
+        Some(_) => {
+            if let Some(timer) = s.change_timers.remove(&id) {
+                ic_cdk_timers::clear_timer(timer);
+            }
+            Ok(format!("Change {} cancelled", id))
+        }
+        None => Err(format!("No pending change with id {}", id)),
+    }
+}
+
+#[query]
+fn get_pending_changes() -> Vec<PendingChange> {
+    state().pending_changes.values().cloned().collect()
+}
+
+#[query]
+fn get_timelock_delay() -> u64 {
+    state().timelock_delay_secs
+}
+
+// Multisig approval for sensitive admin actions
+fn multisig_enabled() -> bool {
+    !state().multisig.signers.is_empty()
+}
+
+fn ensure_multisig_disabled() -> Result<(), String> {
+    if multisig_enabled() {
+        Err("This action requires multisig approval; submit it with `propose_action`".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_signers(signers: &[Principal], threshold: u32) -> Result<(), String> {
+    let unique: HashSet<&Principal> = signers.iter().collect();
+    if unique.len() != signers.len() {
+        return Err("Signer list contains duplicates".to_string());
+    }
+    if threshold == 0 || threshold as usize > signers.len() {
+        return Err(format!("Threshold must be between 1 and the number of signers ({})", signers.len()));
+    }
+    Ok(())
+}
+
+// Bootstraps the signer set; afterwards the set can only be changed through a multisig proposal
+#[update]
+fn set_signers(signers: Vec<Principal>, threshold: u32) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    if let Err(e) = ensure_role(Role::Owner) {
+        return e;
+    }
+    if let Err(e) = ensure_multisig_disabled() {
+        return e;
+    }
+    if let Err(e) = validate_signers(&signers, threshold) {
+        return e;
+    }
+    state().multisig = MultisigConfig { signers, threshold };
+    format!("Multisig enabled with threshold {}", threshold)
+}
+
+#[update]
+async fn propose_action(action: MultisigAction) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    let s = state();
+    let signer = caller();
+    if !s.multisig.signers.contains(&signer) {
+        return "Unauthorized: only multisig signers can propose actions".to_string();
+    }
+    let validation = match &action {
+        MultisigAction::Admin(admin_action) => validate_admin_action(admin_action),
+        MultisigAction::SetSigners { signers, threshold } => validate_signers(signers, *threshold),
+        MultisigAction::WithdrawTreasury { amount, .. } | MultisigAction::FundInsuranceFromTreasury(amount) if *amount <= 0.0 => {
+            Err("Amount must be greater than 0".to_string())
+        }
+        MultisigAction::Pause
+        | MultisigAction::Unpause
+        | MultisigAction::SetOperationPaused { .. }
+        | MultisigAction::CancelChange(_)
+        | MultisigAction::WithdrawTreasury { .. }
+        | MultisigAction::FundInsuranceFromTreasury(_) => Ok(()),
+    };
+    if let Err(e) = validation {
+        return e;
+    }
+    let id = s.next_proposal_id;
+    s.next_proposal_id += 1;
+    s.proposals.insert(id, MultisigProposal {
+        id,
+        action,
+        proposer: signer,
+        approvals: vec![signer],
+        created_at: ic_cdk::api::time(),
+        status: ProposalStatus::Pending,
+    });
+    match try_execute_proposal(id).await {
+        Some(result) => format!("Proposal {} created and executed: {}", id, result),
+        None => format!("Proposal {} created; {} of {} approvals", id, 1, s.multisig.threshold),
+    }
+}
+
+#[update]
+async fn approve_proposal(id: u64) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    let s = state();
+    let signer = caller();
+    if !s.multisig.signers.contains(&signer) {
+        return "Unauthorized: only multisig signers can approve proposals".to_string();
+    }
+    let approvals = match s.proposals.get_mut(&id) {
+        Some(proposal) if proposal.status != ProposalStatus::Pending => {
+            return format!("Proposal {} is no longer pending", id);
+        }
+        Some(proposal) => {
+            if proposal.approvals.contains(&signer) {
+                return format!("You already approved proposal {}", id);
+            }
+            proposal.approvals.push(signer);
+            proposal.approvals.len()
+        }
+        None => return format!("No proposal with id {}", id),
+    };
+    match try_execute_proposal(id).await {
+        Some(result) => format!("Proposal {} approved and executed: {}", id, result),
+        None => format!("Proposal {} approved; {} of {} approvals", id, approvals, s.multisig.threshold),
+    }
+}
+
+// Executes a pending proposal once approvals from current signers reach the threshold
+async fn try_execute_proposal(id: u64) -> Option<String> {
+    let s = state();
+    let proposal = s.proposals.get_mut(&id)?;
+    let valid_approvals = proposal.approvals.iter().filter(|p| s.multisig.signers.contains(p)).count();
+    if valid_approvals < s.multisig.threshold as usize {
+        return None;
+    }
+    // Marked before running so an approval arriving during a treasury transfer can't execute it again
+    proposal.status = ProposalStatus::Executed;
+    let result = match proposal.action.clone() {
+        MultisigAction::Admin(action) => Ok(queue_admin_action(action)),
+        MultisigAction::Pause => {
+            s.is_paused = true;
+            Ok("Contract paused".to_string())
+        }
+        MultisigAction::Unpause => {
+            s.is_paused = false;
+            Ok("Contract unpaused".to_string())
+        }
+        MultisigAction::SetOperationPaused { operation, paused } => Ok(apply_operation_paused(operation, paused)),
+        MultisigAction::SetSigners { signers, threshold } => validate_signers(&signers, threshold).map(|_| {
+            s.multisig = MultisigConfig { signers, threshold };
+            format!("Signer set updated with threshold {}", threshold)
+        }),
+        MultisigAction::CancelChange(change_id) => cancel_pending_change(change_id),
+        MultisigAction::WithdrawTreasury { to, amount, ledger } => execute_treasury_withdrawal(to, amount, ledger).await,
+        MultisigAction::FundInsuranceFromTreasury(amount) => move_treasury_to_insurance(amount),
+    };
+    let (status, message) = match result {
+        Ok(message) => (ProposalStatus::Executed, message),
+        Err(e) => (ProposalStatus::Failed(e.clone()), e),
+    };
+    if let Some(proposal) = s.proposals.get_mut(&id) {
+        proposal.status = status;
+    }
+    Some(message)
+}
+
+#[query]
+fn get_multisig_config() -> MultisigConfig {
+    state().multisig.clone()
+}
+
+#[query]
+fn get_proposals() -> Vec<MultisigProposal> {
+    state().proposals.values().cloned().collect()
+}
+
+// Staker governance
+// Voting power is the liquid stake plus locked positions weighted by their tier multiplier while locked
+fn voting_power(data: &UserData, now: u64) -> u64 {
+    let locked: f64 = data.stake_positions.iter()
+        .map(|position| {
+            let weight = if now < position.unlock_time { position.lock_period.rate_multiplier() } else { 1.0 };
+            position.amount as f64 * weight
+        })
+        .sum();
+    data.staked + locked as u64
+}
+
+#[query]
+fn get_voting_power(p: Option<Principal>) -> u64 {
+    state().users.get(&viewable(p)).map_or(0, |data| voting_power(data, ic_cdk::api::time()))
+}
+
+#[update]
+fn submit_governance_proposal(action: GovernanceAction, description: String) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    let s = state();
+    let proposer = caller();
+    let now = ic_cdk::api::time();
+    let power = s.users.get(&proposer).map_or(0, |data| voting_power(data, now));
+    if power < GOVERNANCE_MIN_PROPOSAL_POWER {
+        return format!("Insufficient voting power to propose. Need {}, have {}.", GOVERNANCE_MIN_PROPOSAL_POWER, power);
+    }
+    let open = s.governance_proposals
+        .values()
+        .filter(|proposal| proposal.proposer == proposer && proposal.status == GovernanceStatus::Open)
+        .count();
+    if open >= MAX_OPEN_PROPOSALS_PER_PROPOSER {
+        return format!("You already have {} open proposals; wait for one to close", open);
+    }
+    let validation = match &action {
+        GovernanceAction::UpdateParams(update) => validate_params(&merge_params(params(), update)),
+        _ => Ok(()),
+    };
+    if let Err(e) = validation {
+        return e;
+    }
+    let id = s.next_governance_proposal_id;
+    s.next_governance_proposal_id += 1;
+    let snapshot: HashMap<Principal, u64> = s.users
+        .iter()
+        .map(|(principal, data)| (*principal, voting_power(data, now)))
+        .filter(|(_, power)| *power > 0)
+        .collect();
+    let total_voting_power = snapshot.values().sum();
+    s.voting_snapshots.insert(id, snapshot);
+    s.governance_proposals.insert(id, GovernanceProposal {
+        id,
+        proposer,
+        description,
+        action,
+        created_at: now,
+        voting_ends_at: now + GOVERNANCE_VOTING_PERIOD_SECS * 1_000_000_000,
+        total_voting_power,
+        yes_votes: 0,
+        no_votes: 0,
+        voters: Vec::new(),
+        status: GovernanceStatus::Open,
+    });
+    ic_cdk_timers::set_timer(Duration::from_secs(GOVERNANCE_VOTING_PERIOD_SECS), move || {
+        ic_cdk::println!("Governance proposal {}: {}", id, finalize_governance(id));
+    });
+    format!("Governance proposal {} submitted. Voting closes in {} seconds.", id, GOVERNANCE_VOTING_PERIOD_SECS)
+}
+
+#[update]
+fn vote(proposal_id: u64, approve: bool) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    let s = state();
+    let voter = caller();
+    let now = ic_cdk::api::time();
+    match s.governance_proposals.get_mut(&proposal_id) {
+        Some(proposal) => {
+            if proposal.status != GovernanceStatus::Open || now >= proposal.voting_ends_at {
+                return format!("Voting on proposal {} is closed", proposal_id);
+            }
+            let power = s.voting_snapshots
+                .get(&proposal_id)
+                .and_then(|snapshot| snapshot.get(&voter))
+                .copied()
+                .unwrap_or(0);
+            if power == 0 {
+                return format!("No voting power: you had no stake when proposal {} was created", proposal_id);
+            }
+            if proposal.voters.contains(&voter) {
+                return format!("You already voted on proposal {}", proposal_id);
+            }
+            proposal.voters.push(voter);
+            if approve {
+                proposal.yes_votes += power;
+            } else {
+                proposal.no_votes += power;
+            }
+            format!("Voted {} on proposal {} with {} voting power", if approve { "yes" } else { "no" }, proposal_id, power)
+        }
+        None => format!("No governance proposal with id {}", proposal_id),
+    }
+}
+
+// Tallies a proposal whose voting period has ended and executes it if it passed
+fn finalize_governance(id: u64) -> String {
+    let s = state();
+    let now = ic_cdk::api::time();
+    let proposal = match s.governance_proposals.get_mut(&id) {
+        Some(proposal) => proposal,
+        None => return format!("No governance proposal with id {}", id),
+    };
+    if proposal.status != GovernanceStatus::Open {
+        return format!("Proposal {} was already finalized: {:?}", id, proposal.status);
+    }
+    if now < proposal.voting_ends_at {
+        return format!("Voting on proposal {} is still open", id);
+    }
+    s.voting_snapshots.remove(&id);
+    let turnout = proposal.yes_votes + proposal.no_votes;
+    let quorum = (proposal.total_voting_power as f64 * GOVERNANCE_QUORUM) as u64;
+    if turnout < quorum || proposal.yes_votes <= proposal.no_votes {
+        proposal.status = GovernanceStatus::Rejected;
+        return format!("Proposal {} rejected (yes: {}, no: {}, quorum: {})", id, proposal.yes_votes, proposal.no_votes, quorum);
+    }
+    proposal.status = GovernanceStatus::Executed;
+    let message = match proposal.action.clone() {
+        GovernanceAction::UpdateParams(update) => queue_admin_action(AdminAction::UpdateParams(update)),
+        GovernanceAction::SetRewardToken { stream, token } => queue_admin_action(AdminAction::SetRewardToken { stream, token }),
+        GovernanceAction::Pause => {
+            s.is_paused = true;
+            "Contract paused".to_string()
+        }
+    };
+    format!("Proposal {} passed: {}", id, message)
+}
+
+// Fallback for a proposal whose timer callback trapped, e.g. when the canister was low on cycles
+#[update]
+fn finalize_governance_proposal(id: u64) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    finalize_governance(id)
+}
+
+#[query]
+fn get_governance_proposals() -> Vec<GovernanceProposal> {
+    state().governance_proposals.values().cloned().collect()
+}
+
+// The anonymous principal (2vxsx-fae) has no key behind it, so nothing may be done in its name
+fn ensure_not_anonymous() -> Result<(), String> {
+    if caller() == Principal::anonymous() {
+        Err("Anonymous callers are not allowed. Please log in with a wallet.".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+// Ingress filtering
+// Every update method; keep in sync when adding one
+const UPDATE_METHODS: &[&str] = &[
+    "grant_role",
+    "revoke_role",
+    "transfer_ownership",
+    "accept_ownership",
+    "pause_contract",
+    "unpause_contract",
+    "set_farming_emission_schedule",
+    "update_params",
+    "set_asset_limits",
+    "set_rate_limit",
+    "issue_export_token",
+    "set_safe_mode_threshold",
+    "accept_cycles",
+    "set_reward_token",
+    "withdraw_treasury",
+    "set_timelock_delay",
+    "execute_change",
+    "cancel_change",
+    "set_signers",
+    "propose_action",
+    "approve_proposal",
+    "submit_governance_proposal",
+    "vote",
+    "finalize_governance_proposal",
+    "set_operation_paused",
+    "register_user",
+    "set_privacy",
+    "add_viewer",
+    "remove_viewer",
+    "add_operator",
+    "remove_operator",
+    "set_auto_compound",
+    "compound_rewards",
+    "claim_reward_token",
+    "check_allowance",
+    "get_real_ckbtc_balance",
+    "deposit_ckbtc",
+    "withdraw_ckbtc",
+    "borrow_ckbtc",
+    "repay_loan_ckbtc",
+    "liquidate",
+    "deposit_insurance",
+    "fund_insurance_from_treasury",
+    "stake_ckbtc",
+    "unstake_ckbtc",
+    "exit_stake_position_early",
+    "complete_unstake",
+    "lend_ckbtc",
+    "unlend_ckbtc",
+    "yield_farm_ckbtc",
+    "unfarm_ckbtc",
+    "claim_staking_rewards",
+    "claim_lending_rewards",
+    "claim_yield_farming_rewards",
+    "claim_all_rewards",
+    "emergency_withdraw_all",
+];
+
+// Decodes the ckBTC amount argument of methods that take one
+fn amount_argument(method: &str) -> Option<Result<f64, candid::Error>> {
+    let bytes = ic_cdk::api::call::arg_data_raw();
+    let amount = match method {
+        "deposit_ckbtc" | "withdraw_ckbtc" | "borrow_ckbtc" | "repay_loan_ckbtc" | "lend_ckbtc" | "unlend_ckbtc"
+        | "yield_farm_ckbtc" | "unfarm_ckbtc" | "deposit_insurance" => {
+            candid::decode_args::<(f64, Option<Principal>)>(&bytes).map(|(amount, _)| amount)
+        }
+        "stake_ckbtc" => candid::decode_args::<(f64, Option<LockPeriod>, Option<Principal>)>(&bytes).map(|(amount, _, _)| amount),
+        "unstake_ckbtc" => candid::decode_args::<(f64, Option<u64>, Option<Principal>)>(&bytes).map(|(amount, _, _)| amount),
+        "withdraw_treasury" => candid::decode_args::<(Principal, f64, Option<Principal>)>(&bytes).map(|(_, amount, _)| amount),
+        "fund_insurance_from_treasury" => candid::decode_args::<(f64,)>(&bytes).map(|(amount,)| amount),
+        _ => return None,
+    };
+    Some(amount)
+}
+
+// Rejects obviously bad ingress messages before they are executed and charged to the canister.
+// Not calling `accept_message` rejects the message; inter-canister calls skip this and rely on the checks in each method.
+#[inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    if caller() == Principal::anonymous() {
+        return;
+    }
+    if !UPDATE_METHODS.contains(&method.as_str()) {
+        return;
+    }
+    match amount_argument(&method) {
+        Some(Ok(amount)) if !amount.is_finite() || amount <= 0.0 => return,
+        Some(Err(_)) => return,
+        _ => {}
+    }
+    ic_cdk::api::call::accept_message();
+}
+
+// Modifier to check if contract is paused
+fn ensure_not_paused() -> Result<(), String> {
+    if state().is_paused {
+        Err("Contract is paused".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+// Repaying and withdrawing unencumbered funds stay available during a global pause or safe mode so users can always exit
+fn check_operation_allowed(operation: Operation) -> Result<(), String> {
+    if state().paused_operations.contains(&operation) {
+        return Err(format!("{:?} is currently paused", operation));
+    }
+    match operation {
+        Operation::Repay | Operation::Withdraw => Ok(()),
+        _ if in_safe_mode() => Err("Canister is low on cycles; only withdrawals and repayments are available".to_string()),
+        _ => ensure_not_paused(),
+    }
+}
+
+fn ensure_operation_allowed(operation: Operation) -> Result<(), String> {
+    check_operation_allowed(operation)?;
+    *state().metrics.operation_requests.entry(operation).or_insert(0) += 1;
+    Ok(())
+}
+
+// Guardians may pause an operation; resuming it needs the owner (or the multisig once enabled)
+#[update]
+fn set_operation_paused(operation: Operation, paused: bool) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    let required = if paused { Role::Guardian } else { Role::Owner };
+    if let Err(e) = ensure_role(required) {
+        return e;
+    }
+    if !paused {
+        if let Err(e) = ensure_multisig_disabled() {
+            return e;
+        }
+    }
+    apply_operation_paused(operation, paused)
+}
+
+fn apply_operation_paused(operation: Operation, paused: bool) -> String {
+    let s = state();
+    if paused {
+        s.paused_operations.insert(operation);
+        format!("{:?} paused", operation)
+    } else {
+        s.paused_operations.remove(&operation);
+        format!("{:?} resumed", operation)
+    }
+}
+
+#[query]
+fn get_pause_flags() -> PauseFlags {
+    let s = state();
+    PauseFlags {
+        global_paused: s.is_paused,
+        safe_mode: in_safe_mode(),
+        operations: ALL_OPERATIONS
+            .into_iter()
+            .map(|operation| OperationStatus {
+                operation,
+                paused: s.paused_operations.contains(&operation),
+                allowed: check_operation_allowed(operation).is_ok(),
+            })
+            .collect(),
+    }
+}
+
+// Collateral backing a user's loans and the debt it has to cover
+fn collateral_value(data: &UserData) -> u64 {
+    data.ckbtc_balance + data.staked + locked_stake(data) + data.lent + data.farmed
+}
+
+fn loan_debt(data: &UserData) -> u64 {
+    data.loans + calculate_interest(data.loans, data.loan_timestamp, params().borrow_rate)
+}
+
+// Roll interest accrued so far into the loan so later rate or balance changes don't reprice it
+fn capitalize_loan_interest(data: &mut UserData, borrow_rate: f64, now: u64) {
+    if data.loans == 0 {
+        return;
+    }
+    let interest = calculate_interest(data.loans, data.loan_timestamp, borrow_rate);
+    data.loans += interest;
+    data.loan_interest += interest;
+    data.loan_timestamp = Some(now);
+}
+
+// Health factor >= 1.0 means the collateral covers the debt at the required collateral ratio
+fn health_factor(data: &UserData) -> f64 {
+    let debt = loan_debt(data);
+    if debt == 0 {
+        return f64::INFINITY;
+    }
+    collateral_value(data) as f64 / (debt as f64 * params().collateral_ratio)
+}
+
+// Rejects removing `amount` of collateral if what is left would no longer cover the user's loans
+fn ensure_unencumbered(data: &UserData, amount: u64) -> Result<(), String> {
+    let required = (loan_debt(data) as f64 * params().collateral_ratio) as u64;
+    let remaining = collateral_value(data).saturating_sub(amount);
+    if required > 0 && remaining < required {
+        return Err(format!("Funds are encumbered by your loans. Collateral after this would be {:.8} ckBTC, required: {:.8} ckBTC", 
+            (remaining as f64)/100_000_000.0, (required as f64)/100_000_000.0));
+    }
+    Ok(())
+}
+
+#[query]
+fn get_loan_debt(p: Option<Principal>) -> u64 {
+    state().users.get(&viewable(p)).map_or(0, loan_debt)
+}
+
+// Stays public regardless of privacy settings so liquidators can find undercollateralized positions
+#[query]
+fn get_health_factor(p: Option<Principal>) -> f64 {
+    state().users.get(&p.unwrap_or(caller())).map_or(f64::INFINITY, health_factor)
+}
+
+// Basic functions
+#[query]
+fn whoami() -> Principal {
+    caller()
+}
+
+#[query]
+fn get_contract_info() -> String {
+    format!(
+        "DeFi Contract - Network: {}, Paused: {}, ckBTC Canister: {}",
+        if IS_TESTNET { "Testnet" } else { "Mainnet" },
+        state().is_paused,
+        CKBTC_CANISTER_ID.to_text()
+    )
+}
+
+#[update]
+fn register_user() -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Registration)?;
+    Ok(process_registration())
+}
+
+fn process_registration() -> String {
+    if let Err(e) = ensure_not_paused() {
+        return e;
+    }
+    let user = caller();
+    let _stats = StatsGuard(user);
+    let s = state();
+
+    if !s.users.contains_key(&user) {
+        s.users.insert(user, UserData {
+            user_principal: user,
+            ckbtc_balance: 0,
+            loans: 0,
+            staked: 0,
+            lent: 0,
+            farmed: 0,
+            stake_timestamp: None,
+            lend_timestamp: None,
+            farm_timestamp: None,
+            loan_timestamp: None,
+            loan_interest: 0,
+            farm_reward_index: 0,
+            farm_pending_rewards: 0,
+            accrued_rewards: HashMap::new(),
+            stake_positions: Vec::new(),
+            pending_unbonds: Vec::new(),
+            auto_compound: false,
+            privacy: PrivacyLevel::default(),
+            authorized_viewers: Vec::new(),
+            operators: Vec::new(),
+        });
+        "User registered successfully".to_string()
+    } else {
+        "User already exists".to_string()
+    }
+}
+
+#[query]
+fn get_user_data(p: Principal) -> Option<UserData> {
+    state().users.get(&viewable(Some(p))).cloned()
+}
+
+// Privacy
+// Users always see their own data; others need to be an admin, an authorized viewer, or the data must be public
+fn can_view(viewer: &Principal, target: &Principal) -> bool {
+    if viewer == target || is_admin(viewer) {
+        return true;
+    }
+    state().users.get(target).is_none_or(|data| data.privacy == PrivacyLevel::Public || data.authorized_viewers.contains(viewer))
+}
+
+// Resolves the principal a per-user query is about, rejecting the call if the caller may not see it
+fn viewable(p: Option<Principal>) -> Principal {
+    let target = p.unwrap_or(caller());
+    if !can_view(&caller(), &target) {
+        ic_cdk::trap(&format!("Unauthorized: the data of {} is private", target));
+    }
+    target
+}
+
+#[update]
+fn set_privacy(level: PrivacyLevel) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    match state().users.get_mut(&caller()) {
+        Some(data) => {
+            data.privacy = level;
+            format!("Your data is now {:?}", level)
+        }
+        None => "User not registered".to_string(),
+    }
+}
+
+#[update]
+fn add_viewer(viewer: Principal) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    match state().users.get_mut(&caller()) {
+        Some(data) => {
+            if data.authorized_viewers.contains(&viewer) {
+                return format!("{} can already view your data", viewer);
+            }
+            data.authorized_viewers.push(viewer);
+            format!("{} can now view your data", viewer)
+        }
+        None => "User not registered".to_string(),
+    }
+}
+
+#[update]
+fn remove_viewer(viewer: Principal) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    match state().users.get_mut(&caller()) {
+        Some(data) => {
+            let before = data.authorized_viewers.len();
+            data.authorized_viewers.retain(|p| *p != viewer);
+            if data.authorized_viewers.len() < before {
+                format!("{} can no longer view your data", viewer)
+            } else {
+                format!("{} was not an authorized viewer", viewer)
+            }
+        }
+        None => "User not registered".to_string(),
+    }
+}
+
+// Operators
+// The caller's own position, or `on_behalf_of`'s if the caller holds an unexpired grant covering `action`
+fn resolve_actor(on_behalf_of: Option<Principal>, action: DelegatedAction) -> Result<Principal, String> {
+    let operator = caller();
+    let owner = match on_behalf_of {
+        Some(owner) if owner != operator => owner,
+        _ => return Ok(operator),
+    };
+    let data = state().users.get(&owner).ok_or_else(|| format!("User {} not registered", owner))?;
+    let now = ic_cdk::api::time();
+    let grant = data.operators
+        .iter()
+        .find(|grant| grant.operator == operator && grant.expires_at.is_none_or(|expires_at| now < expires_at))
+        .ok_or_else(|| format!("Unauthorized: you are not an operator for {}", owner))?;
+    if grant.permission.allows(action) {
+        Ok(owner)
+    } else {
+        Err(format!("Unauthorized: {:?} operators cannot perform {:?} actions", grant.permission, action))
+    }
+}
+
+// Granting again to the same operator replaces the earlier grant
+#[update]
+fn add_operator(operator: Principal, permission: OperatorPermission, expiry: Option<u64>) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    let user = caller();
+    if operator == user {
+        return "You cannot add yourself as an operator".to_string();
+    }
+    let now = ic_cdk::api::time();
+    if expiry.is_some_and(|expires_at| expires_at <= now) {
+        return "Expiry must be in the future".to_string();
+    }
+    match state().users.get_mut(&user) {
+        Some(data) => {
+            data.operators.retain(|grant| grant.operator != operator);
+            data.operators.push(OperatorGrant { operator, permission, granted_at: now, expires_at: expiry });
+            format!("{} can now act on your position with {:?} permission", operator, permission)
+        }
+        None => "User not registered".to_string(),
+    }
+}
+
+#[update]
+fn remove_operator(operator: Principal) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    match state().users.get_mut(&caller()) {
+        Some(data) => {
+            let before = data.operators.len();
+            data.operators.retain(|grant| grant.operator != operator);
+            if data.operators.len() < before {
+                format!("{} is no longer an operator for your position", operator)
+            } else {
+                format!("{} is not an operator for your position", operator)
+            }
+        }
+        None => "User not registered".to_string(),
+    }
+}
+
+#[query]
+fn get_operators(p: Option<Principal>) -> Vec<OperatorGrant> {
+    state().users.get(&viewable(p)).map_or(Vec::new(), |data| data.operators.clone())
+}
+
+#[query]
+fn get_my_data() -> Option<UserData> {
+    state().users.get(&caller()).cloned()
+}
+
+// Helper function to calculate interest/rewards
+fn calculate_interest(amount: u64, timestamp: Option<u64>, rate: f64) -> u64 {
+    if let Some(start_time) = timestamp {
+        let now = ic_cdk::api::time();
+        let seconds = (now - start_time) / 1_000_000_000;
+        let annual_interest = (amount as f64) * rate;
+        let interest = annual_interest * (seconds as f64 / SECONDS_IN_YEAR as f64);
+        interest as u64
+    } else {
+        0
+    }
+}
+
+// Rewards earned by a locked position between its last checkpoint and `now`
+fn stake_position_rewards(position: &StakePosition, now: u64) -> u64 {
+    let boosted_until = now.min(position.unlock_time).max(position.reward_timestamp);
+    let boosted_secs = (boosted_until - position.reward_timestamp) / 1_000_000_000;
+    let base_secs = (now.max(boosted_until) - boosted_until) / 1_000_000_000;
+    let boosted_rate = params().staking_rate * position.lock_period.rate_multiplier();
+    let seconds_weighted = boosted_secs as f64 * boosted_rate + base_secs as f64 * params().staking_rate;
+    ((position.amount as f64) * seconds_weighted / SECONDS_IN_YEAR as f64) as u64
+}
+
+fn locked_stake(data: &UserData) -> u64 {
+    data.stake_positions.iter().map(|position| position.amount).sum()
+}
+
+// Staking rewards owed on the liquid stake and every locked position
+fn pending_staking_rewards(data: &UserData, now: u64) -> u64 {
+    calculate_interest(data.staked, data.stake_timestamp, params().staking_rate)
+        + data.stake_positions.iter().map(|position| stake_position_rewards(position, now)).sum::<u64>()
+}
+
+// Returns all staking rewards earned so far and restarts accrual from `now`
+fn checkpoint_staking_rewards(data: &mut UserData, now: u64) -> u64 {
+    let earned = pending_staking_rewards(data, now);
+    if data.stake_timestamp.is_some() {
+        data.stake_timestamp = Some(now);
+    }
+    for position in data.stake_positions.iter_mut() {
+        position.reward_timestamp = now;
+    }
+    earned
+}
+
+fn queue_unbond(data: &mut UserData, id: u64, amount: u64, now: u64, delay_secs: u64) -> UnbondEntry {
+    let entry = UnbondEntry {
+        id,
+        amount,
+        requested_at: now,
+        available_at: now + delay_secs * 1_000_000_000,
+    };
+    data.pending_unbonds.push(entry.clone());
+    entry
+}
+
+#[query]
+fn get_lock_tiers() -> Vec<LockTier> {
+    [LockPeriod::Days30, LockPeriod::Days90, LockPeriod::Days180, LockPeriod::Days365]
+        .into_iter()
+        .map(|lock_period| LockTier {
+            lock_period,
+            days: lock_period.days(),
+            rate_multiplier: lock_period.rate_multiplier(),
+        })
+        .collect()
+}
+
+#[query]
+fn get_stake_positions(p: Option<Principal>) -> Vec<StakePosition> {
+    state().users.get(&viewable(p)).map_or(Vec::new(), |data| data.stake_positions.clone())
+}
+
+#[query]
+fn get_pending_staking_rewards(p: Option<Principal>) -> u64 {
+    state().users.get(&viewable(p)).map_or(0, |data| pending_staking_rewards(data, ic_cdk::api::time()))
+}
+
+// Roll ckBTC-denominated rewards back into the principal of each bucket without a ledger transfer.
+// Streams paying a different reward token are left untouched, since those rewards are not ckBTC.
+fn compound_user_rewards(pool: &mut FarmingPool, data: &mut UserData, now: u64) -> u64 {
+    let mut compounded = 0;
+    for stream in [RewardStream::Staking, RewardStream::Lending, RewardStream::Farming] {
+        if reward_token(stream).ledger != *CKBTC_CANISTER_ID {
+            continue;
+        }
+        let rewards = take_stream_rewards(pool, data, stream, now);
+        if rewards == 0 {
+            continue;
+        }
+        match stream {
+            RewardStream::Staking => {
+                data.staked += rewards;
+                data.stake_timestamp = Some(now);
+            }
+            RewardStream::Lending => {
+                data.lent += rewards;
+                data.lend_timestamp = Some(now);
+            }
+            RewardStream::Farming => {
+                data.farmed += rewards;
+                pool.total_farmed += rewards;
+                data.farm_timestamp = Some(now);
+            }
+        }
+        compounded += rewards;
+    }
+    compounded
+}
+
+// Returns everything a stream has earned for the user so far and restarts its accrual from `now`
+fn take_stream_rewards(pool: &mut FarmingPool, data: &mut UserData, stream: RewardStream, now: u64) -> u64 {
+    match stream {
+        RewardStream::Staking => checkpoint_staking_rewards(data, now),
+        RewardStream::Lending => {
+            let earned = calculate_interest(data.lent, data.lend_timestamp, params().lending_rate);
+            if data.lend_timestamp.is_some() {
+                data.lend_timestamp = Some(now);
+            }
+            earned
+        }
+        RewardStream::Farming => {
+            update_farming_pool(pool, now);
+            settle_farming_rewards(pool, data);
+            std::mem::take(&mut data.farm_pending_rewards)
+        }
+    }
+}
+
+#[query]
+fn get_pending_lending_rewards(p: Option<Principal>) -> u64 {
+    state().users.get(&viewable(p)).map_or(0, |data| calculate_interest(data.lent, data.lend_timestamp, params().lending_rate))
+}
+
+fn auto_compound_all() {
+    let s = state();
+    if s.is_paused {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    let mut compounded = 0u64;
+    let mut users = 0u32;
+    for data in s.users.values_mut().filter(|data| data.auto_compound) {
+        compounded += compound_user_rewards(&mut s.farming, data, now);
+        users += 1;
+    }
+    refresh_all_user_stats();
+    ic_cdk::println!("Auto-compounded {} sats for {} users", compounded, users);
+}
+
+#[update]
+fn set_auto_compound(enabled: bool, on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    if let Err(e) = ensure_not_paused() {
+        return e;
+    }
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Compound) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    match state().users.get_mut(&user) {
+        Some(data) => {
+            data.auto_compound = enabled;
+            if enabled {
+                format!("Auto-compounding enabled. Rewards are rolled into your positions every {} hours.", AUTO_COMPOUND_INTERVAL_SECS / 3_600)
+            } else {
+                "Auto-compounding disabled".to_string()
+            }
+        }
+        None => "User not registered".to_string(),
+    }
+}
+
+// Rolls ckBTC rewards into the positions that earned them right away instead of waiting for the daily run
+#[update]
+fn compound_rewards(on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    if let Err(e) = ensure_operation_allowed(Operation::Stake) {
+        return e;
+    }
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Compound) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
+    let s = state();
+    match s.users.get_mut(&user) {
+        Some(data) => {
+            let compounded = compound_user_rewards(&mut s.farming, data, ic_cdk::api::time());
+            format!("Compounded {:.8} ckBTC of rewards into your positions", (compounded as f64)/100_000_000.0)
+        }
+        None => "User not registered".to_string(),
+    }
+}
+
+// Emission rate in effect at time `t` (ns)
+fn emission_rate_at(schedule: &EmissionSchedule, t: u64) -> u64 {
+    match schedule {
+        EmissionSchedule::Halving { start_time, initial_reward_per_second, halving_interval_secs } => {
+            if t < *start_time {
+                return 0;
+            }
+            let halvings = (t - start_time) / 1_000_000_000 / halving_interval_secs;
+            if halvings >= MAX_HALVINGS {
+                0
+            } else {
+                initial_reward_per_second >> halvings
+            }
+        }
+        EmissionSchedule::Curve(phases) => phases
+            .iter()
+            .rev()
+            .find(|phase| phase.start_time <= t)
+            .map(|phase| phase.reward_per_second)
+            .unwrap_or(0),
+    }
+}
+
+// Next point in time after `t` at which the emission rate changes, if any
+fn next_emission_change(schedule: &EmissionSchedule, t: u64) -> Option<u64> {
+    match schedule {
+        EmissionSchedule::Halving { start_time, halving_interval_secs, .. } => {
+            if t < *start_time {
+                return Some(*start_time);
+            }
+            let interval_ns = halving_interval_secs.saturating_mul(1_000_000_000);
+            let halvings = (t - start_time) / interval_ns;
+            if halvings >= MAX_HALVINGS {
+                None
+            } else {
+                start_time.checked_add((halvings + 1).saturating_mul(interval_ns))
+            }
+        }
+        EmissionSchedule::Curve(phases) => phases
+            .iter()
+            .find(|phase| phase.start_time > t)
+            .map(|phase| phase.start_time),
+    }
+}
+
+// Total rewards (sats) emitted by the schedule over [from, to)
+fn emitted_between(schedule: &EmissionSchedule, from: u64, to: u64) -> u128 {
+    let mut total = 0u128;
+    let mut t = from;
+    while t < to {
+        let segment_end = next_emission_change(schedule, t).map_or(to, |change| change.min(to));
+        let rate = emission_rate_at(schedule, t) as u128;
+        total += rate * (segment_end - t) as u128 / 1_000_000_000;
+        t = segment_end;
+    }
+    total
+}
+
+// Accrue emissions since the last update into the reward-per-share index
+fn update_farming_pool(pool: &mut FarmingPool, now: u64) {
+    if now <= pool.last_update {
+        return;
+    }
+    if pool.total_farmed > 0 {
+        if let Some(schedule) = &pool.schedule {
+            let emitted = emitted_between(schedule, pool.last_update, now);
+            let fee = (emitted as f64 * params().farming_fee_rate) as u128;
+            pool.accrued_fees += fee as u64;
+            pool.acc_reward_per_share += (emitted - fee) * REWARD_INDEX_SCALE / pool.total_farmed as u128;
+        }
+    }
+    pool.last_update = now;
+}
+
+// Move rewards earned since the user's last checkpoint into their pending balance
+fn settle_farming_rewards(pool: &FarmingPool, data: &mut UserData) {
+    let delta = pool.acc_reward_per_share.saturating_sub(data.farm_reward_index);
+    let earned = data.farmed as u128 * delta / REWARD_INDEX_SCALE;
+    data.farm_pending_rewards += earned as u64;
+    data.farm_reward_index = pool.acc_reward_per_share;
+}
+
+fn current_farming_apr(pool: &FarmingPool) -> f64 {
+    match &pool.schedule {
+        Some(schedule) if pool.total_farmed > 0 => {
+            let rate = emission_rate_at(schedule, ic_cdk::api::time());
+            (rate as f64 * SECONDS_IN_YEAR as f64) / pool.total_farmed as f64
+        }
+        _ => 0.0,
+    }
+}
+
+#[query]
+fn get_farming_pool_info() -> FarmingPoolInfo {
+    let pool = &state().farming;
+    let now = ic_cdk::api::time();
+    FarmingPoolInfo {
+        schedule: pool.schedule.clone(),
+        current_reward_per_second: pool.schedule.as_ref().map_or(0, |schedule| emission_rate_at(schedule, now)),
+        acc_reward_per_share: pool.acc_reward_per_share,
+        total_farmed: pool.total_farmed,
+        current_apr: current_farming_apr(pool),
     }
-    s.is_paused = true;
-    "Contract paused".to_string()
 }
 
-#[update]
-fn unpause_contract() -> String {
+#[query]
+fn get_pending_yield_farming_rewards(p: Option<Principal>) -> u64 {
     let s = state();
-    if Some(caller()) != s.admin {
-        return "Unauthorized: Only admin can unpause".to_string();
+    match s.users.get(&viewable(p)) {
+        Some(data) => {
+            let mut pool = s.farming.clone();
+            update_farming_pool(&mut pool, ic_cdk::api::time());
+            let mut projected = data.clone();
+            settle_farming_rewards(&pool, &mut projected);
+            projected.farm_pending_rewards
+        }
+        None => 0,
     }
-    s.is_paused = false;
-    "Contract unpaused".to_string()
 }
 
-// Modifier to check if contract is paused
-fn ensure_not_paused() -> Result<(), String> {
-    if state().is_paused {
-        Err("Contract is paused".to_string())
-    } else {
-        Ok(())
-    }
+// Reward token configured for a stream, defaulting to ckBTC
+fn reward_token(stream: RewardStream) -> RewardTokenConfig {
+    state().reward_tokens.get(&stream).cloned().unwrap_or_else(|| RewardTokenConfig {
+        ledger: *CKBTC_CANISTER_ID,
+        symbol: "ckBTC".to_string(),
+        fee: CKBTC_TRANSFER_FEE,
+    })
 }
 
-// Basic functions
-#[query]
-fn whoami() -> Principal {
-    caller()
+fn accrue_reward(data: &mut UserData, ledger: Principal, amount: u64) {
+    if amount > 0 {
+        *data.accrued_rewards.entry(ledger).or_insert(0) += amount;
+    }
 }
 
-#[query]
-fn get_contract_info() -> String {
-    format!(
-        "DeFi Contract - Network: {}, Paused: {}, ckBTC Canister: {}",
-        if IS_TESTNET { "Testnet" } else { "Mainnet" },
-        state().is_paused,
-        CKBTC_CANISTER_ID.to_text()
-    )
+fn accrued_reward(data: &UserData, ledger: &Principal) -> u64 {
+    data.accrued_rewards.get(ledger).copied().unwrap_or(0)
 }
 
-#[update]
-fn register_user() -> String {
-    if let Err(e) = ensure_not_paused() {
-        return e;
+fn deduct_accrued_reward(data: &mut UserData, ledger: &Principal, amount: u64) {
+    if let Some(accrued) = data.accrued_rewards.get_mut(ledger) {
+        *accrued = accrued.saturating_sub(amount);
+        if *accrued == 0 {
+            data.accrued_rewards.remove(ledger);
+        }
     }
-    let user = caller();
-    let s = state();
+}
 
-    if !s.users.contains_key(&user) {
-        s.users.insert(user, UserData {
-            user_principal: user,
-            ckbtc_balance: 0,
-            loans: 0,
-            staked: 0,
-            lent: 0,
-            farmed: 0,
-            stake_timestamp: None,
-            lend_timestamp: None,
-            farm_timestamp: None,
-            loan_timestamp: None,
-        });
-        "User registered successfully".to_string()
-    } else {
-        "User already exists".to_string()
+// Pay out everything the user has accrued on a reward ledger, net of that ledger's fee
+async fn pay_accrued_rewards(user: Principal, token: &RewardTokenConfig) -> Result<(u64, Nat), String> {
+    let data = match state().users.get_mut(&user) {
+        Some(data) => data,
+        None => return Err("User not registered".to_string()),
+    };
+    let accrued = accrued_reward(data, &token.ledger);
+    if accrued <= token.fee {
+        return Err(format!("Accrued {} rewards ({}) do not cover the transfer fee ({})", token.symbol, accrued, token.fee));
+    }
+    let payout = accrued - token.fee;
+    // Reserve before the transfer so concurrent claims can't pay the same rewards twice
+    deduct_accrued_reward(data, &token.ledger, accrued);
+    match transfer_from_canister(token.ledger, token.fee, user, payout).await {
+        Ok(tx_id) => Ok((payout, tx_id)),
+        Err(e) => {
+            if let Some(data) = state().users.get_mut(&user) {
+                accrue_reward(data, token.ledger, accrued);
+            }
+            Err(e)
+        }
     }
 }
 
+// Current stream tokens take precedence over retired configs for the same ledger
+fn reward_token_for_ledger(ledger: Principal) -> Option<RewardTokenConfig> {
+    let s = state();
+    get_reward_tokens()
+        .into_iter()
+        .map(|(_, token)| token)
+        .find(|token| token.ledger == ledger)
+        .or_else(|| s.retired_reward_tokens.get(&ledger).cloned())
+}
+
 #[query]
-fn get_user_data(p: Principal) -> Option<UserData> {
-    state().users.get(&p).cloned()
+fn get_retired_reward_tokens() -> Vec<RewardTokenConfig> {
+    state().retired_reward_tokens.values().cloned().collect()
 }
 
 #[query]
-fn get_my_data() -> Option<UserData> {
-    state().users.get(&caller()).cloned()
+fn get_reward_tokens() -> Vec<(RewardStream, RewardTokenConfig)> {
+    [RewardStream::Staking, RewardStream::Lending, RewardStream::Farming]
+        .into_iter()
+        .map(|stream| (stream, reward_token(stream)))
+        .collect()
 }
 
-// Helper function to calculate interest/rewards
-fn calculate_interest(amount: u64, timestamp: Option<u64>, rate: f64) -> u64 {
-    if let Some(start_time) = timestamp {
-        let now = ic_cdk::api::time();
-        let seconds = (now - start_time) / 1_000_000_000;
-        let annual_interest = (amount as f64) * rate;
-        let interest = annual_interest * (seconds as f64 / SECONDS_IN_YEAR as f64);
-        interest as u64
-    } else {
-        0
+// Pays any rewards accrued on `ledger`, e.g. after a stream switched to a different token
+#[update]
+async fn claim_reward_token(ledger: Principal, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Claim)?;
+    Ok(process_reward_token_claim(ledger, on_behalf_of).await)
+}
+
+async fn process_reward_token_claim(ledger: Principal, on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_operation_allowed(Operation::Withdraw) {
+        return e;
+    }
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Withdraw) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let token = match reward_token_for_ledger(ledger) {
+        Some(token) => token,
+        None => return format!("{} has never been configured as a reward token", ledger),
+    };
+    match pay_accrued_rewards(user, &token).await {
+        Ok((payout, tx_id)) => format!("Claimed {} {} (fee: {}). Transaction ID: {}", payout, token.symbol, token.fee, tx_id),
+        Err(e) => format!("Claim failed: {}", e),
     }
 }
 
@@ -255,8 +3076,19 @@ struct Allowance {
     expires_at: Option<u64>,
 }
 
+fn record_ledger_error(method: &'static str, variant: &'static str) {
+    *state().metrics.ledger_errors.entry((method, variant)).or_insert(0) += 1;
+}
+
 #[update]
-async fn check_allowance(owner: Principal) -> Result<Allowance, String> {
+async fn check_allowance(owner: Principal) -> Result<Allowance, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Ledger)?;
+    Ok(fetch_allowance(owner).await?)
+}
+
+// Shared by deposits and repayments, which have already been rate limited
+async fn fetch_allowance(owner: Principal) -> Result<Allowance, String> {
     let args = AllowanceArgs {
         account: Account {
             owner,
@@ -274,6 +3106,9 @@ async fn check_allowance(owner: Principal) -> Result<Allowance, String> {
         (args,),
     ).await;
 
+    if result.is_err() {
+        record_ledger_error("icrc2_allowance", "CallRejected");
+    }
     match result {
         Ok((allowance,)) => Ok(allowance),
         Err(e) => Err(format!("Failed to check allowance: {:?}", e)),
@@ -282,7 +3117,9 @@ async fn check_allowance(owner: Principal) -> Result<Allowance, String> {
 
 // Get real ckBTC balance from the ledger
 #[update]
-async fn get_real_ckbtc_balance(owner: Option<Principal>) -> Result<u64, String> {
+async fn get_real_ckbtc_balance(owner: Option<Principal>) -> Result<u64, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Ledger)?;
     let account_owner = owner.unwrap_or(caller());
     let account = Account {
         owner: account_owner,
@@ -297,7 +3134,7 @@ async fn get_real_ckbtc_balance(owner: Option<Principal>) -> Result<u64, String>
 
     match result {
         Ok((balance,)) => Ok(balance.0.to_u64().unwrap_or(0)),
-        Err(e) => Err(format!("Failed to fetch ckBTC balance: {:?}", e)),
+        Err(e) => Err(CallError::Rejected(format!("Failed to fetch ckBTC balance: {:?}", e))),
     }
 }
 
@@ -319,6 +3156,11 @@ async fn transfer_ckbtc_from_user_to_canister(from: Principal, amount: u64) -> R
         (transfer_from_arg,)
     ).await;
 
+    match &result {
+        Ok((Err(e),)) => record_ledger_error("icrc2_transfer_from", e.variant_name()),
+        Err(_) => record_ledger_error("icrc2_transfer_from", "CallRejected"),
+        _ => {}
+    }
     match result {
         Ok((Ok(tx_id),)) => Ok(tx_id),
         Ok((Err(TransferFromError::InsufficientAllowance { allowance }),)) => {
@@ -333,25 +3175,35 @@ async fn transfer_ckbtc_from_user_to_canister(from: Principal, amount: u64) -> R
 }
 
 async fn transfer_ckbtc_from_canister_to_user(to: Principal, amount: u64) -> Result<Nat, String> {
+    transfer_from_canister(*CKBTC_CANISTER_ID, CKBTC_TRANSFER_FEE, to, amount).await
+}
+
+// ICRC-1 transfer out of the canister's default account on any ledger
+async fn transfer_from_canister(ledger: Principal, fee: u64, to: Principal, amount: u64) -> Result<Nat, String> {
     let transfer_arg = TransferArg {
         from_subaccount: None,
         to: Account { owner: to, subaccount: None },
         amount: Nat::from(amount),
-        fee: Some(Nat::from(CKBTC_TRANSFER_FEE)),
+        fee: Some(Nat::from(fee)),
         memo: None,
         created_at_time: Some(ic_cdk::api::time()),
     };
 
     let result: Result<(Result<Nat, TransferError>,), _> = ic_cdk::call(
-        *CKBTC_CANISTER_ID,
+        ledger,
         "icrc1_transfer",
         (transfer_arg,)
     ).await;
 
+    match &result {
+        Ok((Err(e),)) => record_ledger_error("icrc1_transfer", e.variant_name()),
+        Err(_) => record_ledger_error("icrc1_transfer", "CallRejected"),
+        _ => {}
+    }
     match result {
         Ok((Ok(tx_id),)) => Ok(tx_id),
         Ok((Err(TransferError::InsufficientFunds { balance }),)) => {
-            Err(format!("Canister has insufficient funds. Balance: {} ckBTC", balance))
+            Err(format!("Canister has insufficient funds. Balance: {}", balance))
         }
         Ok((Err(e),)) => Err(format!("Transfer failed: {:?}", e)),
         Err(e) => Err(format!("Call failed: {:?}", e)),
@@ -365,20 +3217,26 @@ fn ckbtc_to_sats(amount: f64) -> u64 {
 
 // Deposit ckBTC (user must approve first)
 #[update]
-async fn deposit_ckbtc(amount: f64) -> String {
-    if let Err(e) = ensure_not_paused() {
-        return e;
-    }
+async fn deposit_ckbtc(amount: f64, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Ledger)?;
+    ensure_operation_allowed(Operation::Deposit)?;
     if amount <= 0.0 {
-        return "Amount must be greater than 0".to_string();
+        return Err(CallError::Rejected("Amount must be greater than 0".to_string()));
     }
     let sats = ckbtc_to_sats(amount);
-    let user = caller();
+    let user = resolve_actor(on_behalf_of, DelegatedAction::Manage)?;
+    check_position_limits(Operation::Deposit, user, sats)?;
+    Ok(process_deposit(user, amount, sats).await)
+}
+
+async fn process_deposit(user: Principal, amount: f64, sats: u64) -> String {
+    let _stats = StatsGuard(user);
     let s = state();
     match s.users.get_mut(&user) {
         Some(data) => {
             let total_required = sats + CKBTC_TRANSFER_FEE;
-            match check_allowance(user).await {
+            match fetch_allowance(user).await {
                 Ok(allowance) => {
                     if allowance.allowance.0.to_u64().unwrap_or(0) < total_required {
                         return format!("Insufficient allowance. Please approve {} ckBTC in your Plug wallet first.", (total_required as f64) / 100_000_000.0);
@@ -400,15 +3258,25 @@ async fn deposit_ckbtc(amount: f64) -> String {
 
 // Withdraw ckBTC
 #[update]
-async fn withdraw_ckbtc(amount: f64) -> String {
-    if let Err(e) = ensure_not_paused() {
+async fn withdraw_ckbtc(amount: f64, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Ledger)?;
+    Ok(process_withdrawal(amount, on_behalf_of).await)
+}
+
+async fn process_withdrawal(amount: f64, on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_operation_allowed(Operation::Withdraw) {
         return e;
     }
     if amount <= 0.0 {
         return "Amount must be greater than 0".to_string();
     }
     let sats = ckbtc_to_sats(amount);
-    let user = caller();
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Withdraw) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
     let s = state();
     match s.users.get_mut(&user) {
         Some(data) => {
@@ -417,6 +3285,9 @@ async fn withdraw_ckbtc(amount: f64) -> String {
                 return format!("Insufficient balance. You have {:.8} ckBTC, need {:.8} (including {:.8} fee)", 
                     (data.ckbtc_balance as f64)/100_000_000.0, (total_required as f64)/100_000_000.0, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0);
             }
+            if let Err(e) = ensure_unencumbered(data, total_required) {
+                return e;
+            }
             match transfer_ckbtc_from_canister_to_user(user, sats).await {
                 Ok(tx_id) => {
                     data.ckbtc_balance -= total_required;
@@ -431,31 +3302,39 @@ async fn withdraw_ckbtc(amount: f64) -> String {
 
 // Borrow ckBTC (requires collateral)
 #[update]
-async fn borrow_ckbtc(amount: f64) -> String {
-    if let Err(e) = ensure_not_paused() {
-        return e;
-    }
+async fn borrow_ckbtc(amount: f64, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Ledger)?;
+    ensure_operation_allowed(Operation::Borrow)?;
     if amount <= 0.0 {
-        return "Amount must be greater than 0".to_string();
+        return Err(CallError::Rejected("Amount must be greater than 0".to_string()));
     }
     let sats = ckbtc_to_sats(amount);
-    let user = caller();
+    let user = resolve_actor(on_behalf_of, DelegatedAction::Withdraw)?;
+    check_position_limits(Operation::Borrow, user, sats)?;
+    Ok(process_borrow(user, amount, sats).await)
+}
+
+async fn process_borrow(user: Principal, amount: f64, sats: u64) -> String {
+    let _stats = StatsGuard(user);
     let s = state();
     match s.users.get_mut(&user) {
         Some(data) => {
-            let required_collateral = (sats as f64 * COLLATERAL_RATIO) as u64;
-            let available_collateral = data.ckbtc_balance + data.staked + data.lent + data.farmed;
+            let required_collateral = (sats as f64 * params().collateral_ratio) as u64;
+            let available_collateral = collateral_value(data);
             if available_collateral < required_collateral {
                 return format!("Insufficient collateral. Required: {:.8} ckBTC, Available: {:.8} ckBTC", 
                     (required_collateral as f64)/100_000_000.0, (available_collateral as f64)/100_000_000.0);
             }
             match transfer_ckbtc_from_canister_to_user(user, sats).await {
                 Ok(tx_id) => {
+                    let now = ic_cdk::api::time();
+                    capitalize_loan_interest(data, params().borrow_rate, now);
                     data.loans += sats;
-                    data.loan_timestamp = Some(ic_cdk::api::time());
+                    data.loan_timestamp = Some(now);
                     data.ckbtc_balance += sats;
                     format!("Borrowed {:.8} ckBTC. Transaction ID: {}. Remember to repay with {}% annual interest.", 
-                        amount, tx_id, (BORROW_RATE * 100.0) as u32)
+                        amount, tx_id, (params().borrow_rate * 100.0) as u32)
                 }
                 Err(e) => format!("Borrow failed: {}", e),
             }
@@ -466,28 +3345,37 @@ async fn borrow_ckbtc(amount: f64) -> String {
 
 // Repay loan
 #[update]
-async fn repay_loan_ckbtc(amount: f64) -> String {
-    if let Err(e) = ensure_not_paused() {
+async fn repay_loan_ckbtc(amount: f64, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Ledger)?;
+    Ok(process_repayment(amount, on_behalf_of).await)
+}
+
+async fn process_repayment(amount: f64, on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_operation_allowed(Operation::Repay) {
         return e;
     }
     if amount <= 0.0 {
         return "Amount must be greater than 0".to_string();
     }
     let sats = ckbtc_to_sats(amount);
-    let user = caller();
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Repay) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
     let s = state();
     match s.users.get_mut(&user) {
         Some(data) => {
             if data.loans == 0 {
                 return "No active loans to repay".to_string();
             }
-            let interest = calculate_interest(data.loans, data.loan_timestamp, BORROW_RATE);
-            let total_debt = data.loans + interest;
+            let total_debt = loan_debt(data);
             if sats > total_debt {
                 return format!("Amount exceeds total debt. Total debt (principal + interest): {:.8} ckBTC", (total_debt as f64)/100_000_000.0);
             }
             let total_required = sats + CKBTC_TRANSFER_FEE;
-            match check_allowance(user).await {
+            match fetch_allowance(user).await {
                 Ok(allowance) => {
                     if allowance.allowance.0.to_u64().unwrap_or(0) < total_required {
                         return format!("Insufficient allowance. Please approve {:.8} ckBTC in your Plug wallet first.", (total_required as f64)/100_000_000.0);
@@ -501,13 +3389,18 @@ async fn repay_loan_ckbtc(amount: f64) -> String {
                         return format!("Insufficient ckBTC balance to repay loan. You need {:.8} (amount + fee), have {:.8}.", (total_required as f64)/100_000_000.0, (data.ckbtc_balance as f64)/100_000_000.0);
                     }
                     data.ckbtc_balance -= total_required;
-                    if sats >= data.loans {
-                        data.loans = 0;
+                    let now = ic_cdk::api::time();
+                    capitalize_loan_interest(data, params().borrow_rate, now);
+                    // Repayments settle interest before principal; the reserve factor's share of it goes to the treasury
+                    let interest_paid = sats.min(data.loan_interest);
+                    data.loan_interest -= interest_paid;
+                    credit_interest_reserves(interest_paid);
+                    data.loans = data.loans.saturating_sub(sats);
+                    if data.loans == 0 {
+                        data.loan_interest = 0;
                         data.loan_timestamp = None;
                         format!("Loan fully repaid. Transaction ID: {}", tx_id)
                     } else {
-                        data.loans -= sats;
-                        data.loan_timestamp = Some(ic_cdk::api::time());
                         format!("Partial repayment of {:.8} ckBTC. Remaining debt: {:.8} ckBTC. Transaction ID: {}", 
                             amount, (data.loans as f64)/100_000_000.0, tx_id)
                     }
@@ -519,17 +3412,257 @@ async fn repay_loan_ckbtc(amount: f64) -> String {
     }
 }
 
+// Liquidation
+// Anyone registered may liquidate an undercollateralized position; collateral is held by the canister, so no ledger calls are needed
+#[update]
+fn liquidate(borrower: Principal) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    if let Err(e) = ensure_operation_allowed(Operation::Liquidate) {
+        return e;
+    }
+    let liquidator = caller();
+    if liquidator == borrower {
+        return "You cannot liquidate your own position".to_string();
+    }
+    let _borrower_stats = StatsGuard(borrower);
+    let _liquidator_stats = StatsGuard(liquidator);
+    let s = state();
+    if !s.users.contains_key(&liquidator) {
+        return "Liquidator must be registered to receive the liquidation bonus".to_string();
+    }
+    let now = ic_cdk::api::time();
+    let (debt, covered, bonus, interest_paid) = match s.users.get_mut(&borrower) {
+        Some(data) => {
+            if data.loans == 0 {
+                return "Borrower has no active loans".to_string();
+            }
+            let health = health_factor(data);
+            if health >= 1.0 {
+                return format!("Position is healthy (health factor {:.4}) and cannot be liquidated", health);
+            }
+            capitalize_loan_interest(data, s.params.borrow_rate, now);
+            let debt = data.loans;
+            let target = debt + (debt as f64 * s.params.liquidation_bonus) as u64;
+            let seized = seize_collateral(&mut s.farming, data, target, now);
+            let covered = seized.min(debt);
+            let interest_paid = covered.min(data.loan_interest);
+            data.loans = 0;
+            data.loan_interest = 0;
+            data.loan_timestamp = None;
+            (debt, covered, seized - covered, interest_paid)
+        }
+        None => return "Borrower not registered".to_string(),
+    };
+    credit_interest_reserves(interest_paid);
+    if let Some(data) = s.users.get_mut(&liquidator) {
+        data.ckbtc_balance += bonus;
+    }
+    let mut message = format!("Liquidated {}: covered {:.8} of {:.8} ckBTC debt, {:.8} ckBTC bonus paid to liquidator.", 
+        borrower, (covered as f64)/100_000_000.0, (debt as f64)/100_000_000.0, (bonus as f64)/100_000_000.0);
+    if covered < debt {
+        let event = record_bad_debt(borrower, liquidator, debt, covered + bonus, debt - covered, now);
+        message.push_str(&format!(" Bad debt of {:.8} ckBTC recorded as event {}: {:.8} covered by insurance, {:.8} socialized across lenders, {:.8} unrecovered.", 
+            (event.shortfall as f64)/100_000_000.0, event.id, (event.covered_by_insurance as f64)/100_000_000.0, 
+            (event.socialized as f64)/100_000_000.0, (event.unrecovered as f64)/100_000_000.0));
+    }
+    message
+}
+
+fn take_up_to(available: &mut u64, remaining: &mut u64) -> u64 {
+    let taken = (*available).min(*remaining);
+    *available -= taken;
+    *remaining -= taken;
+    taken
+}
+
+// Takes up to `target` from a borrower's collateral, most liquid first; rewards earned on it are checkpointed beforehand
+fn seize_collateral(pool: &mut FarmingPool, data: &mut UserData, target: u64, now: u64) -> u64 {
+    for stream in [RewardStream::Staking, RewardStream::Lending, RewardStream::Farming] {
+        let earned = take_stream_rewards(pool, data, stream, now);
+        accrue_reward(data, reward_token(stream).ledger, earned);
+    }
+    let mut remaining = target;
+    take_up_to(&mut data.ckbtc_balance, &mut remaining);
+    pool.total_farmed -= take_up_to(&mut data.farmed, &mut remaining);
+    if data.farmed == 0 {
+        data.farm_timestamp = None;
+    }
+    take_up_to(&mut data.lent, &mut remaining);
+    if data.lent == 0 {
+        data.lend_timestamp = None;
+    }
+    take_up_to(&mut data.staked, &mut remaining);
+    if data.staked == 0 {
+        data.stake_timestamp = None;
+    }
+    for position in data.stake_positions.iter_mut() {
+        take_up_to(&mut position.amount, &mut remaining);
+    }
+    data.stake_positions.retain(|position| position.amount > 0);
+    target - remaining
+}
+
+// Bad debt is absorbed by the insurance fund first, then socialized across lenders
+fn record_bad_debt(borrower: Principal, liquidator: Principal, debt: u64, collateral_seized: u64, shortfall: u64, now: u64) -> BadDebtEvent {
+    let s = state();
+    let covered_by_insurance = shortfall.min(s.insurance.balance);
+    s.insurance.balance -= covered_by_insurance;
+    s.insurance.total_covered += covered_by_insurance;
+    let remainder = shortfall - covered_by_insurance;
+    let socialized = socialize_lender_losses(remainder, now);
+    let event = BadDebtEvent {
+        id: s.next_bad_debt_id,
+        borrower,
+        liquidator,
+        timestamp: now,
+        debt,
+        collateral_seized,
+        shortfall,
+        covered_by_insurance,
+        socialized,
+        unrecovered: remainder - socialized,
+        supply_index: s.supply_index,
+    };
+    s.next_bad_debt_id += 1;
+    s.bad_debt_events.push(event.clone());
+    event
+}
+
+// Writes every lender's balance down pro rata, then records the write-down in the supply index
+fn socialize_lender_losses(loss: u64, now: u64) -> u64 {
+    let s = state();
+    let total_lent: u64 = s.users.values().map(|data| data.lent).sum();
+    if loss == 0 || total_lent == 0 {
+        return 0;
+    }
+    let loss = loss.min(total_lent);
+    let ledger = reward_token(RewardStream::Lending).ledger;
+    let mut socialized = 0;
+    for data in s.users.values_mut().filter(|data| data.lent > 0) {
+        // Interest earned before the write-down is owed on the full balance
+        let earned = take_stream_rewards(&mut s.farming, data, RewardStream::Lending, now);
+        accrue_reward(data, ledger, earned);
+        let share = (loss as u128 * data.lent as u128 / total_lent as u128) as u64;
+        data.lent -= share;
+        if data.lent == 0 {
+            data.lend_timestamp = None;
+        }
+        socialized += share;
+    }
+    s.supply_index *= 1.0 - socialized as f64 / total_lent as f64;
+    refresh_all_user_stats();
+    socialized
+}
+
+#[query]
+fn get_bad_debt_events() -> Vec<BadDebtEvent> {
+    state().bad_debt_events.clone()
+}
+
+// Insurance fund
+#[update]
+fn deposit_insurance(amount: f64, on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    if let Err(e) = ensure_operation_allowed(Operation::Deposit) {
+        return e;
+    }
+    if amount <= 0.0 {
+        return "Amount must be greater than 0".to_string();
+    }
+    let sats = ckbtc_to_sats(amount);
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Withdraw) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
+    let s = state();
+    match s.users.get_mut(&user) {
+        Some(data) => {
+            if data.ckbtc_balance < sats {
+                return format!("Insufficient balance. You have {:.8} ckBTC", (data.ckbtc_balance as f64)/100_000_000.0);
+            }
+            if let Err(e) = ensure_unencumbered(data, sats) {
+                return e;
+            }
+            data.ckbtc_balance -= sats;
+            s.insurance.balance += sats;
+            s.insurance.total_deposited += sats;
+            *s.insurance.contributors.entry(user).or_insert(0) += sats;
+            format!("Contributed {:.8} ckBTC to the insurance fund. Fund balance: {:.8} ckBTC", amount, (s.insurance.balance as f64)/100_000_000.0)
+        }
+        None => "User not registered".to_string(),
+    }
+}
+
+#[update]
+fn fund_insurance_from_treasury(amount: f64) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    if let Err(e) = ensure_role(Role::Treasurer) {
+        return e;
+    }
+    if let Err(e) = ensure_multisig_disabled() {
+        return e;
+    }
+    move_treasury_to_insurance(amount).unwrap_or_else(|e| e)
+}
+
+fn move_treasury_to_insurance(amount: f64) -> Result<String, String> {
+    if amount <= 0.0 {
+        return Err("Amount must be greater than 0".to_string());
+    }
+    let sats = ckbtc_to_sats(amount);
+    let s = state();
+    let available = s.treasury.balances.entry(*CKBTC_CANISTER_ID).or_insert(0);
+    if *available < sats {
+        return Err(format!("Insufficient treasury balance. Available: {:.8} ckBTC", (*available as f64)/100_000_000.0));
+    }
+    *available -= sats;
+    s.insurance.balance += sats;
+    s.insurance.total_from_reserves += sats;
+    Ok(format!("Moved {:.8} ckBTC from the treasury to the insurance fund", amount))
+}
+
+#[query]
+fn get_insurance_fund() -> InsuranceFundInfo {
+    let s = state();
+    InsuranceFundInfo {
+        balance: s.insurance.balance,
+        total_from_reserves: s.insurance.total_from_reserves,
+        total_deposited: s.insurance.total_deposited,
+        total_covered: s.insurance.total_covered,
+        supply_index: s.supply_index,
+    }
+}
+
+#[query]
+fn get_insurance_contribution(p: Option<Principal>) -> u64 {
+    state().insurance.contributors.get(&viewable(p)).copied().unwrap_or(0)
+}
+
 // Stake ckBTC
 #[update]
-async fn stake_ckbtc(amount: f64) -> String {
-    if let Err(e) = ensure_not_paused() {
+async fn stake_ckbtc(amount: f64, lock_period: Option<LockPeriod>, on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    if let Err(e) = ensure_operation_allowed(Operation::Stake) {
         return e;
     }
     if amount <= 0.0 {
         return "Amount must be greater than 0".to_string();
     }
     let sats = ckbtc_to_sats(amount);
-    let user = caller();
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Manage) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
     let s = state();
     match s.users.get_mut(&user) {
         Some(data) => {
@@ -537,65 +3670,229 @@ async fn stake_ckbtc(amount: f64) -> String {
             if data.ckbtc_balance < total_required {
                 return format!("Insufficient ckBTC balance. You need {:.8} (amount + fee), have {:.8}.", (total_required as f64)/100_000_000.0, (data.ckbtc_balance as f64)/100_000_000.0);
             }
+            let now = ic_cdk::api::time();
             data.ckbtc_balance -= total_required;
-            data.staked += sats;
-            data.stake_timestamp = Some(ic_cdk::api::time());
-            format!("Staked {:.8} ckBTC (fee: {:.8}). Earning {}% annual rewards.", 
-                amount, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, (STAKING_RATE * 100.0) as u32)
+            match lock_period {
+                Some(lock_period) => {
+                    let id = s.next_stake_position_id;
+                    s.next_stake_position_id += 1;
+                    let unlock_time = now + lock_period.days() * 86_400 * 1_000_000_000;
+                    data.stake_positions.push(StakePosition {
+                        id,
+                        amount: sats,
+                        lock_period,
+                        start_time: now,
+                        unlock_time,
+                        reward_timestamp: now,
+                    });
+                    format!("Staked {:.8} ckBTC (fee: {:.8}) in position {} locked for {} days. Earning {:.1}% annual rewards.", 
+                        amount, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, id, lock_period.days(), params().staking_rate * lock_period.rate_multiplier() * 100.0)
+                }
+                None => {
+                    data.staked += sats;
+                    data.stake_timestamp = Some(now);
+                    format!("Staked {:.8} ckBTC (fee: {:.8}). Earning {}% annual rewards.", 
+                        amount, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, (params().staking_rate * 100.0) as u32)
+                }
+            }
         }
         None => "User not registered".to_string(),
     }
 }
 
-// Unstake ckBTC
+// Unstake ckBTC into the unbonding queue; funds stop earning and are released by `complete_unstake`
 #[update]
-async fn unstake_ckbtc(amount: f64) -> String {
-    if let Err(e) = ensure_not_paused() {
+async fn unstake_ckbtc(amount: f64, position_id: Option<u64>, on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    if let Err(e) = ensure_operation_allowed(Operation::Withdraw) {
         return e;
     }
     if amount <= 0.0 {
         return "Amount must be greater than 0".to_string();
     }
     let sats = ckbtc_to_sats(amount);
-    let user = caller();
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Withdraw) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
+    let s = state();
+    let now = ic_cdk::api::time();
+    let token = reward_token(RewardStream::Staking);
+    let total_required = sats + CKBTC_TRANSFER_FEE;
+    let data = match s.users.get_mut(&user) {
+        Some(data) => data,
+        None => return "User not registered".to_string(),
+    };
+    if let Err(e) = ensure_unencumbered(data, total_required) {
+        return e;
+    }
+    let (rewards, source) = match position_id {
+        Some(position_id) => {
+            let index = match data.stake_positions.iter().position(|position| position.id == position_id) {
+                Some(index) => index,
+                None => return format!("Stake position {} not found", position_id),
+            };
+            let position = &mut data.stake_positions[index];
+            if now < position.unlock_time {
+                return format!("Stake position {} is locked for another {} seconds. Use `exit_stake_position_early` to exit with a {}% penalty.", 
+                    position_id, (position.unlock_time - now) / 1_000_000_000, (params().early_exit_penalty_rate * 100.0) as u32);
+            }
+            if position.amount < total_required {
+                return format!("Insufficient amount in position. You need {:.8} (amount + fee), have {:.8}.", (total_required as f64)/100_000_000.0, (position.amount as f64)/100_000_000.0);
+            }
+            let rewards = stake_position_rewards(position, now);
+            position.amount -= total_required;
+            position.reward_timestamp = now;
+            if position.amount == 0 {
+                data.stake_positions.remove(index);
+            }
+            (rewards, format!("position {}", position_id))
+        }
+        None => {
+            if data.staked < total_required {
+                return format!("Insufficient staked amount. You need {:.8} (amount + fee), have {:.8}.", (total_required as f64)/100_000_000.0, (data.staked as f64)/100_000_000.0);
+            }
+            let rewards = calculate_interest(data.staked, data.stake_timestamp, params().staking_rate);
+            data.staked -= total_required;
+            data.stake_timestamp = if data.staked == 0 { None } else { Some(now) };
+            (rewards, "liquid stake".to_string())
+        }
+    };
+    accrue_reward(data, token.ledger, rewards);
+    let entry = queue_unbond(data, s.next_unbond_id, sats, now, s.params.unbonding_delay_secs);
+    s.next_unbond_id += 1;
+    format!("Unstaking {:.8} ckBTC from {} (fee: {:.8}). Unbond {} becomes withdrawable in {} seconds via `complete_unstake`. {:.8} {} rewards accrued.", 
+        amount, source, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, entry.id, s.params.unbonding_delay_secs, (rewards as f64)/100_000_000.0, token.symbol)
+}
+
+// Exit a locked position before it unlocks; the penalty is shared among the remaining stakers
+#[update]
+async fn exit_stake_position_early(position_id: u64, on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    if let Err(e) = ensure_operation_allowed(Operation::Withdraw) {
+        return e;
+    }
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Withdraw) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
+    let s = state();
+    let now = ic_cdk::api::time();
+    let (penalty, returned, rewards, token, entry) = match s.users.get_mut(&user) {
+        Some(data) => {
+            let index = match data.stake_positions.iter().position(|position| position.id == position_id) {
+                Some(index) => index,
+                None => return format!("Stake position {} not found", position_id),
+            };
+            if now >= data.stake_positions[index].unlock_time {
+                return format!("Stake position {} is already unlocked. Use `unstake_ckbtc` with the position id instead.", position_id);
+            }
+            if let Err(e) = ensure_unencumbered(data, data.stake_positions[index].amount) {
+                return e;
+            }
+            let position = data.stake_positions.remove(index);
+            let token = reward_token(RewardStream::Staking);
+            let rewards = stake_position_rewards(&position, now);
+            let penalty = (position.amount as f64 * params().early_exit_penalty_rate) as u64;
+            let returned = position.amount - penalty;
+            accrue_reward(data, token.ledger, rewards);
+            let entry = queue_unbond(data, s.next_unbond_id, returned, now, s.params.unbonding_delay_secs);
+            s.next_unbond_id += 1;
+            (penalty, returned, rewards, token, entry)
+        }
+        None => return "User not registered".to_string(),
+    };
+
+    let stake_of = |data: &UserData| data.staked + locked_stake(data);
+    let total_remaining: u64 = s.users.values().map(stake_of).sum();
+    if total_remaining > 0 {
+        for data in s.users.values_mut() {
+            let share = (penalty as u128 * stake_of(data) as u128 / total_remaining as u128) as u64;
+            accrue_reward(data, *CKBTC_CANISTER_ID, share);
+        }
+    }
+
+    format!("Exited position {} early with a {:.8} ckBTC penalty. Unbonding {:.8} ckBTC as unbond {}, withdrawable in {} seconds. {:.8} {} rewards accrued.", 
+        position_id, (penalty as f64)/100_000_000.0, (returned as f64)/100_000_000.0, entry.id, s.params.unbonding_delay_secs, (rewards as f64)/100_000_000.0, token.symbol)
+}
+
+// Transfer every matured unbond to the user's wallet
+#[update]
+async fn complete_unstake(on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Ledger)?;
+    Ok(process_unstake_completion(on_behalf_of).await)
+}
+
+async fn process_unstake_completion(on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_operation_allowed(Operation::Withdraw) {
+        return e;
+    }
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Withdraw) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
     let s = state();
     match s.users.get_mut(&user) {
         Some(data) => {
-            let rewards = calculate_interest(data.staked, data.stake_timestamp, STAKING_RATE);
-            let total_required = sats + CKBTC_TRANSFER_FEE;
-            if data.staked < total_required {
-                return format!("Insufficient staked amount. You need {:.8} (amount + fee), have {:.8}.", (total_required as f64)/100_000_000.0, (data.staked as f64)/100_000_000.0);
+            let now = ic_cdk::api::time();
+            if !data.pending_unbonds.iter().any(|entry| entry.available_at <= now) {
+                return match data.pending_unbonds.iter().map(|entry| entry.available_at).min() {
+                    Some(next) => format!("No unbonds ready yet. Next one is withdrawable in {} seconds.", (next - now) / 1_000_000_000),
+                    None => "No pending unbonds".to_string(),
+                };
             }
-            let total_to_send = sats + rewards;
-            match transfer_ckbtc_from_canister_to_user(user, total_to_send).await {
+            // Take the matured entries out before the transfer so concurrent calls can't pay them twice
+            let (matured, pending): (Vec<UnbondEntry>, Vec<UnbondEntry>) = std::mem::take(&mut data.pending_unbonds)
+                .into_iter()
+                .partition(|entry| entry.available_at <= now);
+            data.pending_unbonds = pending;
+            let total: u64 = matured.iter().map(|entry| entry.amount).sum();
+            match transfer_ckbtc_from_canister_to_user(user, total).await {
                 Ok(tx_id) => {
-                    data.staked -= total_required;
-                    data.ckbtc_balance += sats + rewards;
-                    if data.staked == 0 {
-                        data.stake_timestamp = None;
-                    } else {
-                        data.stake_timestamp = Some(ic_cdk::api::time());
+                    format!("Withdrew {:.8} ckBTC from {} unbond(s). Transaction ID: {}", (total as f64)/100_000_000.0, matured.len(), tx_id)
+                }
+                Err(e) => {
+                    if let Some(data) = state().users.get_mut(&user) {
+                        data.pending_unbonds.extend(matured);
+                        data.pending_unbonds.sort_by_key(|entry| entry.id);
                     }
-                    format!("Unstaked {:.8} ckBTC + {:.8} rewards (fee: {:.8}). Transaction ID: {}", amount, (rewards as f64)/100_000_000.0, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, tx_id)
+                    format!("Completing unstake failed: {}", e)
                 }
-                Err(e) => format!("Unstaking failed: {}", e),
             }
         }
         None => "User not registered".to_string(),
     }
 }
 
+#[query]
+fn get_pending_unbonds(p: Option<Principal>) -> Vec<UnbondEntry> {
+    state().users.get(&viewable(p)).map_or(Vec::new(), |data| data.pending_unbonds.clone())
+}
+
 // Lend ckBTC
 #[update]
-async fn lend_ckbtc(amount: f64) -> String {
-    if let Err(e) = ensure_not_paused() {
-        return e;
-    }
+async fn lend_ckbtc(amount: f64, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_operation_allowed(Operation::Lend)?;
     if amount <= 0.0 {
-        return "Amount must be greater than 0".to_string();
+        return Err(CallError::Rejected("Amount must be greater than 0".to_string()));
     }
     let sats = ckbtc_to_sats(amount);
-    let user = caller();
+    let user = resolve_actor(on_behalf_of, DelegatedAction::Manage)?;
+    check_position_limits(Operation::Lend, user, sats)?;
+    Ok(process_lend(user, amount, sats))
+}
+
+fn process_lend(user: Principal, amount: f64, sats: u64) -> String {
+    let _stats = StatsGuard(user);
     let s = state();
     match s.users.get_mut(&user) {
         Some(data) => {
@@ -607,7 +3904,7 @@ async fn lend_ckbtc(amount: f64) -> String {
             data.lent += sats;
             data.lend_timestamp = Some(ic_cdk::api::time());
             format!("Lent {:.8} ckBTC (fee: {:.8}). Earning {}% annual rewards.", 
-                amount, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, (LENDING_REWARD * 100.0) as u32)
+                amount, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, (params().lending_rate * 100.0) as u32)
         }
         None => "User not registered".to_string(),
     }
@@ -615,34 +3912,50 @@ async fn lend_ckbtc(amount: f64) -> String {
 
 // Unlend ckBTC
 #[update]
-async fn unlend_ckbtc(amount: f64) -> String {
-    if let Err(e) = ensure_not_paused() {
+async fn unlend_ckbtc(amount: f64, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Ledger)?;
+    Ok(process_unlend(amount, on_behalf_of).await)
+}
+
+async fn process_unlend(amount: f64, on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_operation_allowed(Operation::Withdraw) {
         return e;
     }
     if amount <= 0.0 {
         return "Amount must be greater than 0".to_string();
     }
     let sats = ckbtc_to_sats(amount);
-    let user = caller();
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Withdraw) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
     let s = state();
     match s.users.get_mut(&user) {
         Some(data) => {
-            let rewards = calculate_interest(data.lent, data.lend_timestamp, LENDING_REWARD);
+            let token = reward_token(RewardStream::Lending);
+            let rewards = calculate_interest(data.lent, data.lend_timestamp, params().lending_rate);
             let total_required = sats + CKBTC_TRANSFER_FEE;
             if data.lent < total_required {
                 return format!("Insufficient lent amount. You need {:.8} (amount + fee), have {:.8}.", (total_required as f64)/100_000_000.0, (data.lent as f64)/100_000_000.0);
             }
-            let total_to_send = sats + rewards;
+            if let Err(e) = ensure_unencumbered(data, total_required) {
+                return e;
+            }
+            // Rewards in another token are accrued for a later claim instead of riding along with the principal
+            let ckbtc_rewards = if token.ledger == *CKBTC_CANISTER_ID { rewards } else { 0 };
+            let total_to_send = sats + ckbtc_rewards;
             match transfer_ckbtc_from_canister_to_user(user, total_to_send).await {
                 Ok(tx_id) => {
                     data.lent -= total_required;
-                    data.ckbtc_balance += sats + rewards;
+                    accrue_reward(data, token.ledger, rewards - ckbtc_rewards);
                     if data.lent == 0 {
                         data.lend_timestamp = None;
                     } else {
                         data.lend_timestamp = Some(ic_cdk::api::time());
                     }
-                    format!("Unlent {:.8} ckBTC + {:.8} rewards (fee: {:.8}). Transaction ID: {}", amount, (rewards as f64)/100_000_000.0, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, tx_id)
+                    format!("Unlent {:.8} ckBTC + {:.8} {} rewards (fee: {:.8}). Transaction ID: {}", amount, (rewards as f64)/100_000_000.0, token.symbol, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, tx_id)
                 }
                 Err(e) => format!("Unlending failed: {}", e),
             }
@@ -653,15 +3966,22 @@ async fn unlend_ckbtc(amount: f64) -> String {
 
 // Yield farm ckBTC
 #[update]
-async fn yield_farm_ckbtc(amount: f64) -> String {
-    if let Err(e) = ensure_not_paused() {
+async fn yield_farm_ckbtc(amount: f64, on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_not_anonymous() {
+        return e;
+    }
+    if let Err(e) = ensure_operation_allowed(Operation::Farm) {
         return e;
     }
     if amount <= 0.0 {
         return "Amount must be greater than 0".to_string();
     }
     let sats = ckbtc_to_sats(amount);
-    let user = caller();
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Manage) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
     let s = state();
     match s.users.get_mut(&user) {
         Some(data) => {
@@ -669,11 +3989,15 @@ async fn yield_farm_ckbtc(amount: f64) -> String {
             if data.ckbtc_balance < total_required {
                 return format!("Insufficient ckBTC balance. You need {:.8} (amount + fee), have {:.8}.", (total_required as f64)/100_000_000.0, (data.ckbtc_balance as f64)/100_000_000.0);
             }
+            let now = ic_cdk::api::time();
+            update_farming_pool(&mut s.farming, now);
+            settle_farming_rewards(&s.farming, data);
             data.ckbtc_balance -= total_required;
             data.farmed += sats;
-            data.farm_timestamp = Some(ic_cdk::api::time());
-            format!("Started yield farming with {:.8} ckBTC (fee: {:.8}). Earning {}% annual rewards.", 
-                amount, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, (YIELD_FARMING_REWARD * 100.0) as u32)
+            data.farm_timestamp = Some(now);
+            s.farming.total_farmed += sats;
+            format!("Started yield farming with {:.8} ckBTC (fee: {:.8}). Current farming APR: {:.2}%.", 
+                amount, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, current_farming_apr(&s.farming) * 100.0)
         }
         None => "User not registered".to_string(),
     }
@@ -681,148 +4005,183 @@ async fn yield_farm_ckbtc(amount: f64) -> String {
 
 // Stop yield farming
 #[update]
-async fn unfarm_ckbtc(amount: f64) -> String {
-    if let Err(e) = ensure_not_paused() {
+async fn unfarm_ckbtc(amount: f64, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Ledger)?;
+    Ok(process_unfarm(amount, on_behalf_of).await)
+}
+
+async fn process_unfarm(amount: f64, on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_operation_allowed(Operation::Withdraw) {
         return e;
     }
     if amount <= 0.0 {
         return "Amount must be greater than 0".to_string();
     }
     let sats = ckbtc_to_sats(amount);
-    let user = caller();
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Withdraw) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
     let s = state();
     match s.users.get_mut(&user) {
         Some(data) => {
-            let rewards = calculate_interest(data.farmed, data.farm_timestamp, YIELD_FARMING_REWARD);
             let total_required = sats + CKBTC_TRANSFER_FEE;
             if data.farmed < total_required {
                 return format!("Insufficient farmed amount. You need {:.8} (amount + fee), have {:.8}.", (total_required as f64)/100_000_000.0, (data.farmed as f64)/100_000_000.0);
             }
-            let total_to_send = sats + rewards;
+            if let Err(e) = ensure_unencumbered(data, total_required) {
+                return e;
+            }
+            let token = reward_token(RewardStream::Farming);
+            update_farming_pool(&mut s.farming, ic_cdk::api::time());
+            settle_farming_rewards(&s.farming, data);
+            // Take the rewards and the position out before the transfer so concurrent calls can't spend them too
+            let rewards = std::mem::take(&mut data.farm_pending_rewards);
+            data.farmed -= total_required;
+            s.farming.total_farmed -= total_required;
+            let ckbtc_rewards = if token.ledger == *CKBTC_CANISTER_ID { rewards } else { 0 };
+            let total_to_send = sats + ckbtc_rewards;
             match transfer_ckbtc_from_canister_to_user(user, total_to_send).await {
                 Ok(tx_id) => {
-                    data.farmed -= total_required;
-                    data.ckbtc_balance += sats + rewards;
-                    if data.farmed == 0 {
-                        data.farm_timestamp = None;
-                    } else {
-                        data.farm_timestamp = Some(ic_cdk::api::time());
+                    if let Some(data) = state().users.get_mut(&user) {
+                        accrue_reward(data, token.ledger, rewards - ckbtc_rewards);
+                        data.farm_timestamp = if data.farmed == 0 { None } else { Some(ic_cdk::api::time()) };
+                    }
+                    format!("Stopped farming {:.8} ckBTC + {:.8} {} rewards (fee: {:.8}). Transaction ID: {}", amount, (rewards as f64)/100_000_000.0, token.symbol, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, tx_id)
+                }
+                Err(e) => {
+                    let s = state();
+                    update_farming_pool(&mut s.farming, ic_cdk::api::time());
+                    if let Some(data) = s.users.get_mut(&user) {
+                        settle_farming_rewards(&s.farming, data);
+                        data.farm_pending_rewards += rewards;
+                        data.farmed += total_required;
+                        s.farming.total_farmed += total_required;
                     }
-                    format!("Stopped farming {:.8} ckBTC + {:.8} rewards (fee: {:.8}). Transaction ID: {}", amount, (rewards as f64)/100_000_000.0, (CKBTC_TRANSFER_FEE as f64)/100_000_000.0, tx_id)
+                    format!("Unfarming failed: {}", e)
                 }
-                Err(e) => format!("Unfarming failed: {}", e),
             }
         }
         None => "User not registered".to_string(),
     }
 }
 
-// Claim individual rewards functions
-#[update]
-async fn claim_staking_rewards() -> String {
-    if let Err(e) = ensure_not_paused() {
+// Claim rewards functions
+async fn claim_rewards(streams: &[RewardStream], mode: ClaimMode, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Claim)?;
+    Ok(process_reward_claim(streams, mode, on_behalf_of).await)
+}
+
+async fn process_reward_claim(streams: &[RewardStream], mode: ClaimMode, on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_operation_allowed(Operation::Withdraw) {
         return e;
     }
 
-    let user = caller();
+    let action = match mode {
+        ClaimMode::ToBalance => DelegatedAction::Manage,
+        ClaimMode::Withdraw => DelegatedAction::Withdraw,
+    };
+    let user = match resolve_actor(on_behalf_of, action) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
     let s = state();
-    
-    match s.users.get_mut(&user) {
-        Some(data) => {
-            let rewards = calculate_interest(data.staked, data.stake_timestamp, STAKING_RATE);
-            if rewards == 0 {
-                return "No staking rewards to claim".to_string();
+    let data = match s.users.get_mut(&user) {
+        Some(data) => data,
+        None => return "User not registered".to_string(),
+    };
+
+    // Everything earned is first booked against its reward ledger, then paid from there
+    let now = ic_cdk::api::time();
+    let mut tokens: Vec<RewardTokenConfig> = Vec::new();
+    for &stream in streams {
+        let token = reward_token(stream);
+        let earned = take_stream_rewards(&mut s.farming, data, stream, now);
+        accrue_reward(data, token.ledger, earned);
+        if !tokens.iter().any(|t| t.ledger == token.ledger) {
+            tokens.push(token);
+        }
+    }
+
+    let names = streams.iter().map(|stream| format!("{:?}", stream).to_lowercase()).collect::<Vec<_>>().join(", ");
+    let mut results = Vec::new();
+    for token in tokens {
+        // Re-borrow each time: an earlier ledger transfer may have let other calls change the user's data
+        let data = match state().users.get_mut(&user) {
+            Some(data) => data,
+            None => break,
+        };
+        let accrued = accrued_reward(data, &token.ledger);
+        if accrued == 0 {
+            continue;
+        }
+        match mode {
+            ClaimMode::ToBalance if token.ledger == *CKBTC_CANISTER_ID => {
+                deduct_accrued_reward(data, &token.ledger, accrued);
+                data.ckbtc_balance += accrued;
+                results.push(format!("Credited {} ckBTC to your balance", accrued));
             }
-            // User must have enough staked to cover the fee
-            if data.staked < CKBTC_TRANSFER_FEE {
-                return format!("Insufficient staked amount to cover the fee. Need at least {} staked.", CKBTC_TRANSFER_FEE);
+            ClaimMode::ToBalance => {
+                results.push(format!("{} {} held in your reward balance; withdraw it with `claim_reward_token`", accrued, token.symbol));
             }
-            match transfer_ckbtc_from_canister_to_user(user, rewards).await {
-                Ok(tx_id) => {
-                    data.staked -= CKBTC_TRANSFER_FEE;
-                    data.ckbtc_balance += rewards;
-                    data.stake_timestamp = Some(ic_cdk::api::time());
-                    format!("Claimed {} ckBTC as staking rewards (fee: {}). Transaction ID: {}", rewards, CKBTC_TRANSFER_FEE, tx_id)
+            ClaimMode::Withdraw => match pay_accrued_rewards(user, &token).await {
+                Ok((payout, tx_id)) => {
+                    results.push(format!("Withdrew {} {} (fee: {}). Transaction ID: {}", payout, token.symbol, token.fee, tx_id));
                 }
-                Err(e) => format!("Claim failed: {}", e),
-            }
+                Err(e) => results.push(format!("{} withdrawal failed: {}", token.symbol, e)),
+            },
         }
-        None => "User not registered".to_string(),
+    }
+
+    if results.is_empty() {
+        format!("No {} rewards to claim", names)
+    } else {
+        format!("Claimed {} rewards. {}", names, results.join(". "))
     }
 }
 
 #[update]
-async fn claim_lending_rewards() -> String {
-    if let Err(e) = ensure_not_paused() {
-        return e;
-    }
+async fn claim_staking_rewards(mode: Option<ClaimMode>, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    claim_rewards(&[RewardStream::Staking], mode.unwrap_or(ClaimMode::ToBalance), on_behalf_of).await
+}
 
-    let user = caller();
-    let s = state();
-    
-    match s.users.get_mut(&user) {
-        Some(data) => {
-            let rewards = calculate_interest(data.lent, data.lend_timestamp, LENDING_REWARD);
-            if rewards == 0 {
-                return "No lending rewards to claim".to_string();
-            }
-            if data.lent < CKBTC_TRANSFER_FEE {
-                return format!("Insufficient lent amount to cover the fee. Need at least {} lent.", CKBTC_TRANSFER_FEE);
-            }
-            match transfer_ckbtc_from_canister_to_user(user, rewards).await {
-                Ok(tx_id) => {
-                    data.lent -= CKBTC_TRANSFER_FEE;
-                    data.ckbtc_balance += rewards;
-                    data.lend_timestamp = Some(ic_cdk::api::time());
-                    format!("Claimed {} ckBTC as lending rewards (fee: {}). Transaction ID: {}", rewards, CKBTC_TRANSFER_FEE, tx_id)
-                }
-                Err(e) => format!("Claim failed: {}", e),
-            }
-        }
-        None => "User not registered".to_string(),
-    }
+#[update]
+async fn claim_lending_rewards(mode: Option<ClaimMode>, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    claim_rewards(&[RewardStream::Lending], mode.unwrap_or(ClaimMode::ToBalance), on_behalf_of).await
 }
 
 #[update]
-async fn claim_yield_farming_rewards() -> String {
-    if let Err(e) = ensure_not_paused() {
-        return e;
-    }
+async fn claim_yield_farming_rewards(mode: Option<ClaimMode>, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    claim_rewards(&[RewardStream::Farming], mode.unwrap_or(ClaimMode::ToBalance), on_behalf_of).await
+}
 
-    let user = caller();
-    let s = state();
-    
-    match s.users.get_mut(&user) {
-        Some(data) => {
-            let rewards = calculate_interest(data.farmed, data.farm_timestamp, YIELD_FARMING_REWARD);
-            if rewards == 0 {
-                return "No yield farming rewards to claim".to_string();
-            }
-            if data.farmed < CKBTC_TRANSFER_FEE {
-                return format!("Insufficient farmed amount to cover the fee. Need at least {} farmed.", CKBTC_TRANSFER_FEE);
-            }
-            match transfer_ckbtc_from_canister_to_user(user, rewards).await {
-                Ok(tx_id) => {
-                    data.farmed -= CKBTC_TRANSFER_FEE;
-                    data.ckbtc_balance += rewards;
-                    data.farm_timestamp = Some(ic_cdk::api::time());
-                    format!("Claimed {} ckBTC as yield farming rewards (fee: {}). Transaction ID: {}", rewards, CKBTC_TRANSFER_FEE, tx_id)
-                }
-                Err(e) => format!("Claim failed: {}", e),
-            }
-        }
-        None => "User not registered".to_string(),
-    }
+#[update]
+async fn claim_all_rewards(mode: Option<ClaimMode>, on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    claim_rewards(&[RewardStream::Staking, RewardStream::Lending, RewardStream::Farming], mode.unwrap_or(ClaimMode::ToBalance), on_behalf_of).await
 }
 
 // Emergency functions
 #[update]
-async fn emergency_withdraw_all() -> String {
-    if let Err(e) = ensure_not_paused() {
+async fn emergency_withdraw_all(on_behalf_of: Option<Principal>) -> Result<String, CallError> {
+    ensure_not_anonymous()?;
+    ensure_rate_limit(RateLimitCategory::Ledger)?;
+    Ok(process_emergency_withdrawal(on_behalf_of).await)
+}
+
+async fn process_emergency_withdrawal(on_behalf_of: Option<Principal>) -> String {
+    if let Err(e) = ensure_operation_allowed(Operation::Withdraw) {
         return e;
     }
 
-    let user = caller();
+    let user = match resolve_actor(on_behalf_of, DelegatedAction::Withdraw) {
+        Ok(user) => user,
+        Err(e) => return e,
+    };
+    let _stats = StatsGuard(user);
     let s = state();
     
     match s.users.get_mut(&user) {
@@ -831,39 +4190,102 @@ async fn emergency_withdraw_all() -> String {
                 return "Cannot withdraw all while having active loans. Please repay loans first.".to_string();
             }
 
-            let total_staked = data.staked;
+            // Locked stake positions are not released here; they stay in place until they unlock.
+            // The liquid stake goes through the unbonding queue, and only matured unbonds are paid out.
+            let now = ic_cdk::api::time();
+            let total_unbonded: u64 = data.pending_unbonds.iter()
+                .filter(|entry| entry.available_at <= now)
+                .map(|entry| entry.amount)
+                .sum();
             let total_lent = data.lent;
             let total_farmed = data.farmed;
             let balance = data.ckbtc_balance;
 
-            let staking_rewards = calculate_interest(data.staked, data.stake_timestamp, STAKING_RATE);
-            let lending_rewards = calculate_interest(data.lent, data.lend_timestamp, LENDING_REWARD);
-            let farming_rewards = calculate_interest(data.farmed, data.farm_timestamp, YIELD_FARMING_REWARD);
+            let staking_rewards = calculate_interest(data.staked, data.stake_timestamp, params().staking_rate);
+            let lending_rewards = calculate_interest(data.lent, data.lend_timestamp, params().lending_rate);
+            update_farming_pool(&mut s.farming, ic_cdk::api::time());
+            settle_farming_rewards(&s.farming, data);
+            let farming_rewards = data.farm_pending_rewards;
+
+            // Only ckBTC-denominated rewards leave in this transfer; other tokens stay accrued for `claim_reward_token`
+            let ckbtc_ledger = *CKBTC_CANISTER_ID;
+            let staking_ledger = reward_token(RewardStream::Staking).ledger;
+            let lending_ledger = reward_token(RewardStream::Lending).ledger;
+            let farming_ledger = reward_token(RewardStream::Farming).ledger;
+            let in_ckbtc = |ledger: Principal, amount: u64| if ledger == ckbtc_ledger { amount } else { 0 };
+            let accrued_ckbtc = accrued_reward(data, &ckbtc_ledger);
 
-            let total_amount = total_staked + total_lent + total_farmed + balance + 
-                              staking_rewards + lending_rewards + farming_rewards;
+            let total_amount = total_unbonded + total_lent + total_farmed + balance + 
+                              in_ckbtc(staking_ledger, staking_rewards) + in_ckbtc(lending_ledger, lending_rewards) +
+                              in_ckbtc(farming_ledger, farming_rewards) + accrued_ckbtc;
 
             if total_amount <= CKBTC_TRANSFER_FEE {
                 return "No funds to withdraw or not enough to cover the fee".to_string();
             }
 
             let withdrawable = total_amount - CKBTC_TRANSFER_FEE;
+            // Everything paid out leaves the position before the transfer so concurrent calls can't pay it again.
+            // Matured unbonds come out of the queue, and the liquid stake goes into it.
+            let (matured_unbonds, pending): (Vec<UnbondEntry>, Vec<UnbondEntry>) = std::mem::take(&mut data.pending_unbonds)
+                .into_iter()
+                .partition(|entry| entry.available_at <= now);
+            data.pending_unbonds = pending;
+            s.farming.total_farmed -= total_farmed;
+            data.farm_pending_rewards = 0;
+            deduct_accrued_reward(data, &ckbtc_ledger, accrued_ckbtc);
+            accrue_reward(data, staking_ledger, staking_rewards - in_ckbtc(staking_ledger, staking_rewards));
+            accrue_reward(data, lending_ledger, lending_rewards - in_ckbtc(lending_ledger, lending_rewards));
+            accrue_reward(data, farming_ledger, farming_rewards - in_ckbtc(farming_ledger, farming_rewards));
+            let staked = data.staked;
+            let stake_unbond = if staked > 0 {
+                let entry = queue_unbond(data, s.next_unbond_id, staked, now, s.params.unbonding_delay_secs);
+                s.next_unbond_id += 1;
+                Some(entry.id)
+            } else {
+                None
+            };
+            data.ckbtc_balance = 0;
+            data.staked = 0;
+            data.lent = 0;
+            data.farmed = 0;
+            data.stake_timestamp = None;
+            data.lend_timestamp = None;
+            data.farm_timestamp = None;
 
             match transfer_ckbtc_from_canister_to_user(user, withdrawable).await {
                 Ok(tx_id) => {
-                    // Reset all user data
-                    data.ckbtc_balance = 0;
-                    data.staked = 0;
-                    data.lent = 0;
-                    data.farmed = 0;
-                    data.stake_timestamp = None;
-                    data.lend_timestamp = None;
-                    data.farm_timestamp = None;
-
-                    format!("Emergency withdrawal successful. Total withdrawn: {} ckBTC (includes all rewards, minus fee). Transaction ID: {}", 
+                    format!("Emergency withdrawal successful. Total withdrawn: {} ckBTC (includes all ckBTC rewards, minus fee; liquid stake moved to unbonding). Transaction ID: {}", 
                         withdrawable, tx_id)
                 }
-                Err(e) => format!("Emergency withdrawal failed: {}", e),
+                Err(e) => {
+                    // Restore the position; ckBTC rewards earned up to now stay accrued instead of being recomputed
+                    let s = state();
+                    let now = ic_cdk::api::time();
+                    update_farming_pool(&mut s.farming, now);
+                    if let Some(data) = s.users.get_mut(&user) {
+                        data.pending_unbonds.retain(|entry| Some(entry.id) != stake_unbond);
+                        data.pending_unbonds.extend(matured_unbonds);
+                        data.pending_unbonds.sort_by_key(|entry| entry.id);
+                        data.ckbtc_balance += balance;
+                        if staked > 0 {
+                            data.staked += staked;
+                            data.stake_timestamp.get_or_insert(now);
+                        }
+                        if total_lent > 0 {
+                            data.lent += total_lent;
+                            data.lend_timestamp.get_or_insert(now);
+                        }
+                        if total_farmed > 0 {
+                            settle_farming_rewards(&s.farming, data);
+                            data.farmed += total_farmed;
+                            s.farming.total_farmed += total_farmed;
+                            data.farm_timestamp.get_or_insert(now);
+                        }
+                        accrue_reward(data, ckbtc_ledger, accrued_ckbtc + in_ckbtc(staking_ledger, staking_rewards)
+                            + in_ckbtc(lending_ledger, lending_rewards) + in_ckbtc(farming_ledger, farming_rewards));
+                    }
+                    format!("Emergency withdrawal failed: {}", e)
+                }
             }
         }
         None => "User not registered".to_string(),
@@ -871,44 +4293,272 @@ async fn emergency_withdraw_all() -> String {
 }
 
 // Statistics and info functions
+// Platform statistics
+fn refresh_user_stats(user: Principal) {
+    let s = state();
+    let stats = &mut s.stats;
+    let new = s.users.get(&user).map(UserFootprint::of);
+    let old = match new {
+        Some(new) => stats.footprints.insert(user, new),
+        None => stats.footprints.remove(&user),
+    };
+    let old = old.unwrap_or_default();
+    let new = new.unwrap_or_default();
+    let totals = &mut stats.totals;
+    totals.balance = totals.balance - old.balance + new.balance;
+    totals.loans = totals.loans - old.loans + new.loans;
+    totals.staked = totals.staked - old.staked + new.staked;
+    totals.locked = totals.locked - old.locked + new.locked;
+    totals.lent = totals.lent - old.lent + new.lent;
+    totals.farmed = totals.farmed - old.farmed + new.farmed;
+    totals.unbonding = totals.unbonding - old.unbonding + new.unbonding;
+    let count = |counter: &mut u64, was: bool, is: bool| match (was, is) {
+        (false, true) => *counter += 1,
+        (true, false) => *counter -= 1,
+        _ => {}
+    };
+    count(&mut stats.active_borrowers, old.loans > 0, new.loans > 0);
+    count(&mut stats.active_stakers, old.staked + old.locked > 0, new.staked + new.locked > 0);
+    count(&mut stats.active_lenders, old.lent > 0, new.lent > 0);
+    count(&mut stats.active_farmers, old.farmed > 0, new.farmed > 0);
+    certify_user(user);
+}
+
+// Used after operations that already touch every user, such as parameter changes and auto-compounding
+fn refresh_all_user_stats() {
+    let users: Vec<Principal> = state().users.keys().copied().collect();
+    for user in users {
+        refresh_user_stats(user);
+    }
+}
+
+// Certified data
+fn user_certification_key(user: &Principal) -> Vec<u8> {
+    [b"user/".as_slice(), user.as_slice()].concat()
+}
+
+fn candid_hash<T: CandidType>(value: &T) -> Hash {
+    Sha256::digest(candid::encode_one(value).expect("Candid encoding failed")).into()
+}
+
+fn user_balances(user: &Principal) -> Option<UserBalances> {
+    state().stats.footprints.get(user).map(|footprint| UserBalances {
+        ckbtc_balance: footprint.balance as u64,
+        loans: footprint.loans as u64,
+        staked: footprint.staked as u64,
+        locked_stake: footprint.locked as u64,
+        lent: footprint.lent as u64,
+        farmed: footprint.farmed as u64,
+        unbonding: footprint.unbonding as u64,
+    })
+}
+
+fn certified_stats() -> CertifiedStats {
+    let stats = &state().stats;
+    let totals = &stats.totals;
+    CertifiedStats {
+        total_users: stats.footprints.len() as u64,
+        active_borrowers: stats.active_borrowers,
+        active_stakers: stats.active_stakers,
+        active_lenders: stats.active_lenders,
+        active_farmers: stats.active_farmers,
+        total_deposits: Nat::from(totals.balance),
+        total_loans: Nat::from(totals.loans),
+        total_staked: Nat::from(totals.staked),
+        total_locked_stake: Nat::from(totals.locked),
+        total_lent: Nat::from(totals.lent),
+        total_farmed: Nat::from(totals.farmed),
+        total_unbonding: Nat::from(totals.unbonding),
+    }
+}
+
+// Re-hashes the user's leaf and the stats leaf, then publishes the new root hash
+fn certify_user(user: Principal) {
+    let key = user_certification_key(&user);
+    match user_balances(&user) {
+        Some(balances) => state().certified.0.insert(key, candid_hash(&balances)),
+        None => state().certified.0.delete(&key),
+    }
+    certify_stats();
+}
+
+fn certify_stats() {
+    let tree = &mut state().certified.0;
+    tree.insert(CERTIFIED_STATS_KEY.to_vec(), candid_hash(&certified_stats()));
+    ic_cdk::api::set_certified_data(&tree.root_hash());
+}
+
+fn witness_for(key: &[u8]) -> Vec<u8> {
+    let witness = state().certified.0.witness(key);
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().expect("CBOR encoding failed");
+    witness.serialize(&mut serializer).expect("CBOR encoding failed");
+    serializer.into_inner()
+}
+
+#[query]
+fn get_certified_balances(p: Option<Principal>) -> CertifiedUserBalances {
+    let user = viewable(p);
+    CertifiedUserBalances {
+        balances: user_balances(&user),
+        certificate: ic_cdk::api::data_certificate().unwrap_or_default(),
+        witness: witness_for(&user_certification_key(&user)),
+    }
+}
+
+#[query]
+fn get_certified_stats() -> CertifiedPlatformStats {
+    CertifiedPlatformStats {
+        stats: certified_stats(),
+        certificate: ic_cdk::api::data_certificate().unwrap_or_default(),
+        witness: witness_for(CERTIFIED_STATS_KEY),
+    }
+}
+
+// User listing
+const MAX_LIST_USERS_LIMIT: u32 = 500;
+// Rows per CSV export page; keeps responses well under the 2 MB reply limit
+const MAX_EXPORT_ROWS: u32 = 5_000;
+const EXPORT_TOKEN_TTL_SECS: u64 = 900;
+
+fn matches_filter(data: &UserData, filter: &UserFilter) -> bool {
+    filter.has_loans.is_none_or(|has_loans| (data.loans > 0) == has_loans)
+        && filter.health_factor_below.is_none_or(|threshold| health_factor(data) < threshold)
+        && filter.min_balance.is_none_or(|min_balance| data.ckbtc_balance >= min_balance)
+}
+
+// Users in principal order, starting after `cursor`
 #[query]
-fn get_platform_stats() -> String {
+fn list_users(cursor: Option<Principal>, limit: u32, filter: Option<UserFilter>) -> Result<UserPage, String> {
+    ensure_admin()?;
     let s = state();
-    let mut total_deposits = 0u64;
-    let mut total_loans = 0u64;
-    let mut total_staked = 0u64;
-    let mut total_lent = 0u64;
-    let mut total_farmed = 0u64;
-    let mut user_count = 0u32;
+    let filter = filter.unwrap_or_default();
+    let limit = limit.clamp(1, MAX_LIST_USERS_LIMIT) as usize;
+    let start = match cursor {
+        Some(cursor) => std::ops::Bound::Excluded(cursor),
+        None => std::ops::Bound::Unbounded,
+    };
+    let mut users = Vec::new();
+    let mut next_cursor = None;
+    for principal in s.stats.footprints.range((start, std::ops::Bound::Unbounded)).map(|(principal, _)| principal) {
+        if users.len() == limit {
+            next_cursor = users.last().map(|data: &UserData| data.user_principal);
+            break;
+        }
+        if let Some(data) = s.users.get(principal).filter(|data| matches_filter(data, &filter)) {
+            users.push(data.clone());
+        }
+    }
+    Ok(UserPage { users, next_cursor })
+}
+
+// Audit export of every position as CSV, paged like `list_users`
+#[query]
+fn export_positions_csv(cursor: Option<Principal>, limit: Option<u32>) -> Result<PositionsExport, String> {
+    ensure_admin()?;
+    Ok(positions_csv(cursor, limit))
+}
+
+// Short-lived token for `/export/positions.csv?token=...`; it grants the export only while the issuer stays an admin
+#[update]
+async fn issue_export_token() -> Result<ExportTokenInfo, String> {
+    ensure_not_anonymous()?;
+    ensure_admin()?;
+    let (bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, message)| format!("Failed to generate a token: {:?} {}", code, message))?;
+    let token: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let now = ic_cdk::api::time();
+    let expires_at = now + EXPORT_TOKEN_TTL_SECS * 1_000_000_000;
+    let tokens = &mut state().export_tokens;
+    tokens.retain(|_, issued| issued.expires_at > now);
+    tokens.insert(token.clone(), ExportToken { issued_to: caller(), expires_at });
+    Ok(ExportTokenInfo { token, expires_at })
+}
 
-    for (_, data) in s.users.iter() {
-        total_deposits += data.ckbtc_balance;
-        total_loans += data.loans;
-        total_staked += data.staked;
-        total_lent += data.lent;
-        total_farmed += data.farmed;
-        user_count += 1;
+fn export_token_valid(token: &str) -> bool {
+    state().export_tokens
+        .get(token)
+        .is_some_and(|issued| issued.expires_at > ic_cdk::api::time() && is_admin(&issued.issued_to))
+}
+
+fn positions_csv(cursor: Option<Principal>, limit: Option<u32>) -> PositionsExport {
+    let s = state();
+    let limit = limit.unwrap_or(MAX_EXPORT_ROWS).clamp(1, MAX_EXPORT_ROWS) as usize;
+    let start = match cursor {
+        Some(cursor) => std::ops::Bound::Excluded(cursor),
+        None => std::ops::Bound::Unbounded,
+    };
+    let mut csv = String::from("principal,ckbtc_balance,loans,loan_debt,staked,locked_stake,stake_positions,lent,farmed,unbonding,health_factor\n");
+    let mut rows = 0;
+    let mut next_cursor = None;
+    let mut last = None;
+    for principal in s.stats.footprints.range((start, std::ops::Bound::Unbounded)).map(|(principal, _)| principal) {
+        if rows == limit {
+            next_cursor = last;
+            break;
+        }
+        let data = match s.users.get(principal) {
+            Some(data) => data,
+            None => continue,
+        };
+        let health = health_factor(data);
+        csv.push_str(&format!("{},{},{},{},{},{},{},{},{},{},{}\n",
+            data.user_principal,
+            data.ckbtc_balance,
+            data.loans,
+            loan_debt(data),
+            data.staked,
+            locked_stake(data),
+            data.stake_positions.len(),
+            data.lent,
+            data.farmed,
+            data.pending_unbonds.iter().map(|entry| entry.amount).sum::<u64>(),
+            if health.is_finite() { format!("{:.6}", health) } else { String::new() },
+        ));
+        rows += 1;
+        last = Some(*principal);
     }
+    PositionsExport { csv, next_cursor }
+}
 
-    format!(
-        "Platform Statistics:\n\
-        Network: {}\n\
-        Total Users: {}\n\
-        Total Deposits: {} ckBTC\n\
-        Total Loans: {} ckBTC\n\
-        Total Staked: {} ckBTC\n\
-        Total Lent: {} ckBTC\n\
-        Total Yield Farming: {} ckBTC\n\
-        Contract Status: {}",
-        if IS_TESTNET { "Testnet" } else { "Mainnet" },
-        user_count,
-        total_deposits,
-        total_loans,
-        total_staked,
-        total_lent,
-        total_farmed,
-        if s.is_paused { "Paused" } else { "Active" }
-    )
+// Refreshes the user's footprint when an endpoint returns, whichever path it returns by
+struct StatsGuard(Principal);
+
+impl Drop for StatsGuard {
+    fn drop(&mut self) {
+        refresh_user_stats(self.0);
+    }
+}
+
+#[query]
+fn get_platform_stats() -> PlatformStats {
+    let s = state();
+    let stats = &s.stats;
+    let totals = &stats.totals;
+    let supplied = totals.supplied();
+    PlatformStats {
+        network: if IS_TESTNET { "Testnet" } else { "Mainnet" }.to_string(),
+        total_users: stats.footprints.len() as u64,
+        active_borrowers: stats.active_borrowers,
+        active_stakers: stats.active_stakers,
+        active_lenders: stats.active_lenders,
+        active_farmers: stats.active_farmers,
+        total_deposits: Nat::from(totals.balance),
+        total_loans: Nat::from(totals.loans),
+        total_staked: Nat::from(totals.staked),
+        total_locked_stake: Nat::from(totals.locked),
+        total_lent: Nat::from(totals.lent),
+        total_farmed: Nat::from(totals.farmed),
+        total_unbonding: Nat::from(totals.unbonding),
+        tvl: Nat::from(supplied + totals.unbonding),
+        utilization: if supplied == 0 { 0.0 } else { totals.loans as f64 / supplied as f64 },
+        staking_apr: s.params.staking_rate,
+        lending_apr: s.params.lending_rate,
+        borrow_apr: s.params.borrow_rate,
+        farming_apr: current_farming_apr(&s.farming),
+        paused: s.is_paused,
+    }
 }
 
 // Helper function for Plug wallet integration guide
@@ -924,7 +4574,7 @@ fn get_integration_guide() -> String {
            - `deposit_ckbtc(amount)` - Deposit ckBTC\n\
            - `stake_ckbtc(amount)` - Earn {}% annual rewards\n\
            - `lend_ckbtc(amount)` - Earn {}% annual rewards\n\
-           - `yield_farm_ckbtc(amount)` - Earn {}% annual rewards\n\
+           - `yield_farm_ckbtc(amount)` - Share {} sats/sec of farming emissions (current APR {:.2}%)\n\
            - `borrow_ckbtc(amount)` - Borrow at {}% annual rate\n\
         4. View your data: `get_my_data()`\n\
         5. Check pending rewards: `get_pending_*_rewards()`\n\n\
@@ -932,11 +4582,420 @@ fn get_integration_guide() -> String {
         💡 All amounts are in ckBTC (1 BTC = 100,000,000 ckBTC)\n\
         💡 Transfer fee: {} ckBTC per transaction",
         ic_cdk::id().to_text(),
-        (STAKING_RATE * 100.0) as u32,
-        (LENDING_REWARD * 100.0) as u32,
-        (YIELD_FARMING_REWARD * 100.0) as u32,
-        (BORROW_RATE * 100.0) as u32,
+        (params().staking_rate * 100.0) as u32,
+        (params().lending_rate * 100.0) as u32,
+        state().farming.schedule.as_ref().map_or(0, |schedule| emission_rate_at(schedule, ic_cdk::api::time())),
+        current_farming_apr(&state().farming) * 100.0,
+        (params().borrow_rate * 100.0) as u32,
         if IS_TESTNET { "Testnet" } else { "Mainnet" },
         CKBTC_TRANSFER_FEE
     )
-}
\ No newline at end of file
+}
+
+// HTTP interface
+// Read-only JSON endpoints for dashboards and curl, served from `http_request`
+const HTTP_DEFAULT_PAGE_SIZE: usize = 50;
+const HTTP_MAX_PAGE_SIZE: usize = 500;
+
+#[derive(CandidType, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    body: Vec<u8>,
+}
+
+fn json_response(status_code: u16, body: Value) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+        ],
+        body: body.to_string().into_bytes(),
+    }
+}
+
+fn json_error(status_code: u16, message: &str) -> HttpResponse {
+    json_response(status_code, json!({ "error": message }))
+}
+
+fn split_url(url: &str) -> (&str, HashMap<&str, &str>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let params = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .collect();
+    (path.trim_end_matches('/'), params)
+}
+
+// `offset` and `limit` query parameters, with the limit clamped to HTTP_MAX_PAGE_SIZE
+fn page_params(query: &HashMap<&str, &str>) -> Result<(usize, usize), String> {
+    let parse = |key: &str, default: usize| match query.get(key) {
+        Some(value) => value.parse::<usize>().map_err(|_| format!("Invalid `{}` parameter: {}", key, value)),
+        None => Ok(default),
+    };
+    let offset = parse("offset", 0)?;
+    let limit = parse("limit", HTTP_DEFAULT_PAGE_SIZE)?.min(HTTP_MAX_PAGE_SIZE);
+    Ok((offset, limit))
+}
+
+#[query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    if req.method != "GET" {
+        return json_error(405, "Only GET is supported");
+    }
+    let (path, query) = split_url(&req.url);
+    match path {
+        "/stats" => json_response(200, stats_json()),
+        "/params" => json_response(200, params_json()),
+        "/rates" => json_response(200, rates_json()),
+        "/health" => json_response(200, health_json()),
+        "/metrics" => metrics_response(),
+        "/export/positions.csv" => positions_csv_response(&query),
+        "/users" => match page_params(&query) {
+            Ok((offset, limit)) => json_response(200, users_page_json(offset, limit)),
+            Err(e) => json_error(400, &e),
+        },
+        _ => match path.strip_prefix("/users/") {
+            Some(text) => match Principal::from_text(text) {
+                Ok(principal) => match state().users.get(&principal).filter(|data| data.privacy == PrivacyLevel::Public) {
+                    Some(data) => json_response(200, user_json(data)),
+                    None => json_error(404, "User not found"),
+                },
+                Err(_) => json_error(400, "Invalid principal"),
+            },
+            None => json_error(404, "Not found"),
+        },
+    }
+}
+
+fn stats_json() -> Value {
+    let s = state();
+    let stats = get_platform_stats();
+    let totals = &s.stats.totals;
+    json!({
+        "network": if IS_TESTNET { "testnet" } else { "mainnet" },
+        "total_users": stats.total_users,
+        "active_borrowers": stats.active_borrowers,
+        "total_deposits": totals.balance,
+        "total_loans": totals.loans,
+        "total_staked": totals.staked + totals.locked,
+        "total_lent": totals.lent,
+        "total_farmed": totals.farmed,
+        "total_unbonding": totals.unbonding,
+        "total_supplied": totals.supplied(),
+        "utilization": stats.utilization,
+        "treasury_ckbtc": s.treasury.balances.get(&*CKBTC_CANISTER_ID).copied().unwrap_or(0),
+        "insurance_fund": s.insurance.balance,
+        "supply_index": s.supply_index,
+        "paused": s.is_paused,
+    })
+}
+
+fn params_json() -> Value {
+    let limits = asset_limits(*CKBTC_CANISTER_ID);
+    json!({
+        "params": params(),
+        "ckbtc_limits": {
+            "supply_cap": limits.supply_cap,
+            "borrow_cap": limits.borrow_cap,
+            "max_borrow_per_user": limits.max_borrow_per_user,
+            "min_position_size": limits.min_position_size,
+        },
+        "timelock_delay_secs": state().timelock_delay_secs,
+        "transfer_fee": CKBTC_TRANSFER_FEE,
+    })
+}
+
+fn rates_json() -> Value {
+    let p = params();
+    let supplied = total_supplied();
+    json!({
+        "staking_rate": p.staking_rate,
+        "lending_rate": p.lending_rate,
+        "borrow_rate": p.borrow_rate,
+        "farming_apr": current_farming_apr(&state().farming),
+        "utilization": if supplied == 0 { 0.0 } else { total_borrowed() as f64 / supplied as f64 },
+        "lock_tiers": get_lock_tiers().iter().map(|tier| json!({
+            "days": tier.days,
+            "staking_rate": p.staking_rate * tier.rate_multiplier,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+fn health_json() -> Value {
+    let s = state();
+    json!({
+        "status": if s.is_paused { "paused" } else if in_safe_mode() { "safe_mode" } else { "ok" },
+        "cycles_balance": ic_cdk::api::canister_balance128().to_string(),
+        "paused_operations": s.paused_operations.iter().map(|operation| format!("{:?}", operation)).collect::<Vec<_>>(),
+        "users": s.users.len(),
+        "pending_changes": s.pending_changes.len(),
+        "time": ic_cdk::api::time(),
+    })
+}
+
+fn user_json(data: &UserData) -> Value {
+    json!({
+        "principal": data.user_principal.to_text(),
+        "ckbtc_balance": data.ckbtc_balance,
+        "loan_debt": loan_debt(data),
+        "staked": data.staked,
+        "locked_stake": locked_stake(data),
+        "lent": data.lent,
+        "farmed": data.farmed,
+        "pending_unbonds": data.pending_unbonds.iter().map(|entry| entry.amount).sum::<u64>(),
+        "health_factor": Some(health_factor(data)).filter(|health| health.is_finite()),
+        "auto_compound": data.auto_compound,
+    })
+}
+
+// HTTP requests are unauthenticated, so per-user endpoints only serve users whose data is public
+fn public_users() -> impl Iterator<Item = &'static UserData> {
+    let s = state();
+    s.stats.footprints
+        .keys()
+        .filter_map(|principal| s.users.get(principal))
+        .filter(|data| data.privacy == PrivacyLevel::Public)
+}
+
+// Users are ordered by principal so pages stay stable between requests
+fn users_page_json(offset: usize, limit: usize) -> Value {
+    let users: Vec<Value> = public_users()
+        .skip(offset)
+        .take(limit)
+        .map(user_json)
+        .collect();
+    json!({
+        "total": public_users().count(),
+        "offset": offset,
+        "limit": limit,
+        "users": users,
+    })
+}
+
+// Prometheus text exposition for `/metrics`
+fn metrics_response() -> HttpResponse {
+    let s = state();
+    let mut out = String::new();
+    let mut gauge = |name: &str, help: &str, value: f64| {
+        out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"));
+    };
+    let supplied = total_supplied();
+    let borrowed = total_borrowed();
+    let totals = &s.stats.totals;
+    gauge("bitfinance_total_deposits_sats", "ckBTC held as user balances", totals.balance as f64);
+    gauge("bitfinance_total_loans_sats", "Recorded loan balances", borrowed as f64);
+    gauge("bitfinance_total_staked_sats", "Liquid and locked stake", (totals.staked + totals.locked) as f64);
+    gauge("bitfinance_total_lent_sats", "ckBTC supplied to lending", totals.lent as f64);
+    gauge("bitfinance_total_farmed_sats", "ckBTC in the farming pool", totals.farmed as f64);
+    gauge("bitfinance_users", "Registered users", s.stats.footprints.len() as f64);
+    gauge("bitfinance_utilization", "Borrowed over supplied", if supplied == 0 { 0.0 } else { borrowed as f64 / supplied as f64 });
+    gauge("bitfinance_treasury_reserve_sats", "ckBTC held by the treasury", s.treasury.balances.get(&*CKBTC_CANISTER_ID).copied().unwrap_or(0) as f64);
+    gauge("bitfinance_insurance_fund_sats", "ckBTC held by the insurance fund", s.insurance.balance as f64);
+    gauge("bitfinance_cycles_balance", "Canister cycles balance", ic_cdk::api::canister_balance128() as f64);
+    gauge("bitfinance_cycles_burn_per_day", "Average cycles burned per day over the sample window", cycles_burn_rate_per_day().unwrap_or(0) as f64);
+    gauge("bitfinance_safe_mode", "1 while the canister is below its cycles threshold", if in_safe_mode() { 1.0 } else { 0.0 });
+    gauge("bitfinance_stable_memory_bytes", "Stable memory size", (ic_cdk::api::stable::stable64_size() * 65_536) as f64);
+
+    out.push_str("# HELP bitfinance_operation_requests_total Requests admitted per operation type\n# TYPE bitfinance_operation_requests_total counter\n");
+    for operation in ALL_OPERATIONS {
+        let count = s.metrics.operation_requests.get(&operation).copied().unwrap_or(0);
+        out.push_str(&format!("bitfinance_operation_requests_total{{operation=\"{:?}\"}} {}\n", operation, count));
+    }
+    out.push_str("# HELP bitfinance_ledger_errors_total Ledger call failures per method and error variant\n# TYPE bitfinance_ledger_errors_total counter\n");
+    for ((method, variant), count) in s.metrics.ledger_errors.iter() {
+        out.push_str(&format!("bitfinance_ledger_errors_total{{method=\"{}\",error=\"{}\"}} {}\n", method, variant, count));
+    }
+
+    HttpResponse {
+        status_code: 200,
+        headers: vec![("Content-Type".to_string(), "text/plain; version=0.0.4".to_string())],
+        body: out.into_bytes(),
+    }
+}
+
+// Admin-only CSV export; page with `cursor` (the `X-Next-Cursor` header of the previous page) and `limit`
+fn positions_csv_response(query: &HashMap<&str, &str>) -> HttpResponse {
+    if !query.get("token").is_some_and(|token| export_token_valid(token)) {
+        return json_error(403, "A valid export token is required; admins can get one from `issue_export_token`");
+    }
+    let cursor = match query.get("cursor").map(Principal::from_text) {
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(_)) => return json_error(400, "Invalid `cursor` parameter"),
+        None => None,
+    };
+    let limit = match query.get("limit").map(|text| text.parse::<u32>()) {
+        Some(Ok(limit)) => Some(limit),
+        Some(Err(_)) => return json_error(400, "Invalid `limit` parameter"),
+        None => None,
+    };
+    let export = positions_csv(cursor, limit);
+    let mut headers = vec![
+        ("Content-Type".to_string(), "text/csv".to_string()),
+        ("Content-Disposition".to_string(), "attachment; filename=\"positions.csv\"".to_string()),
+        ("Cache-Control".to_string(), "no-store".to_string()),
+    ];
+    if let Some(next_cursor) = export.next_cursor {
+        headers.push(("X-Next-Cursor".to_string(), next_cursor.to_text()));
+    }
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body: export.csv.into_bytes(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: u64 = 1_000_000_000;
+
+    fn halving(start_time: u64) -> EmissionSchedule {
+        EmissionSchedule::Halving { start_time, initial_reward_per_second: 1_000, halving_interval_secs: 100 }
+    }
+
+    fn curve() -> EmissionSchedule {
+        EmissionSchedule::Curve(vec![
+            EmissionPhase { start_time: 10 * SEC, reward_per_second: 50 },
+            EmissionPhase { start_time: 20 * SEC, reward_per_second: 20 },
+            EmissionPhase { start_time: 30 * SEC, reward_per_second: 0 },
+        ])
+    }
+
+    #[test]
+    fn halving_rate_drops_exactly_at_each_boundary() {
+        let schedule = halving(5 * SEC);
+        assert_eq!(emission_rate_at(&schedule, 5 * SEC - 1), 0);
+        assert_eq!(emission_rate_at(&schedule, 5 * SEC), 1_000);
+        assert_eq!(emission_rate_at(&schedule, 105 * SEC - 1), 1_000);
+        assert_eq!(emission_rate_at(&schedule, 105 * SEC), 500);
+        assert_eq!(emission_rate_at(&schedule, 205 * SEC), 250);
+    }
+
+    #[test]
+    fn halving_next_change_lands_on_boundaries() {
+        let schedule = halving(5 * SEC);
+        assert_eq!(next_emission_change(&schedule, 0), Some(5 * SEC));
+        assert_eq!(next_emission_change(&schedule, 5 * SEC), Some(105 * SEC));
+        assert_eq!(next_emission_change(&schedule, 105 * SEC - 1), Some(105 * SEC));
+        assert_eq!(next_emission_change(&schedule, 105 * SEC), Some(205 * SEC));
+        assert_eq!(next_emission_change(&schedule, (5 + 100 * MAX_HALVINGS) * SEC), None);
+    }
+
+    #[test]
+    fn halving_emissions_split_across_a_boundary() {
+        let schedule = halving(0);
+        assert_eq!(emitted_between(&schedule, 0, 100 * SEC), 100_000);
+        assert_eq!(emitted_between(&schedule, 90 * SEC, 110 * SEC), 10 * 1_000 + 10 * 500);
+        assert_eq!(emitted_between(&schedule, 0, 100 * SEC) + emitted_between(&schedule, 100 * SEC, 300 * SEC), emitted_between(&schedule, 0, 300 * SEC));
+    }
+
+    #[test]
+    fn halving_emissions_stop_after_the_last_halving() {
+        let schedule = halving(0);
+        let end = 100 * MAX_HALVINGS * SEC;
+        assert_eq!(emission_rate_at(&schedule, end), 0);
+        assert_eq!(emitted_between(&schedule, end, end + 1_000 * SEC), 0);
+    }
+
+    #[test]
+    fn curve_phase_edges() {
+        let schedule = curve();
+        assert_eq!(emission_rate_at(&schedule, 10 * SEC - 1), 0);
+        assert_eq!(emission_rate_at(&schedule, 10 * SEC), 50);
+        assert_eq!(emission_rate_at(&schedule, 20 * SEC - 1), 50);
+        assert_eq!(emission_rate_at(&schedule, 20 * SEC), 20);
+        assert_eq!(emission_rate_at(&schedule, 30 * SEC), 0);
+        assert_eq!(next_emission_change(&schedule, 0), Some(10 * SEC));
+        assert_eq!(next_emission_change(&schedule, 10 * SEC), Some(20 * SEC));
+        assert_eq!(next_emission_change(&schedule, 30 * SEC), None);
+    }
+
+    #[test]
+    fn curve_emissions_sum_each_phase() {
+        let schedule = curve();
+        assert_eq!(emitted_between(&schedule, 0, 40 * SEC), 10 * 50 + 10 * 20);
+        assert_eq!(emitted_between(&schedule, 15 * SEC, 25 * SEC), 5 * 50 + 5 * 20);
+        assert_eq!(emitted_between(&schedule, 20 * SEC, 20 * SEC), 0);
+    }
+
+    #[test]
+    fn farming_pool_accrues_emissions_net_of_fees() {
+        let mut pool = FarmingPool { schedule: Some(curve()), last_update: 10 * SEC, total_farmed: 1_000, ..Default::default() };
+        update_farming_pool(&mut pool, 20 * SEC);
+        let emitted = 500u128;
+        let fee = (emitted as f64 * params().farming_fee_rate) as u128;
+        assert_eq!(pool.accrued_fees, fee as u64);
+        assert_eq!(pool.acc_reward_per_share, (emitted - fee) * REWARD_INDEX_SCALE / 1_000);
+        assert_eq!(pool.last_update, 20 * SEC);
+
+        let index = pool.acc_reward_per_share;
+        update_farming_pool(&mut pool, 15 * SEC);
+        assert_eq!(pool.acc_reward_per_share, index);
+        assert_eq!(pool.last_update, 20 * SEC);
+    }
+
+    #[test]
+    fn empty_farming_pool_only_moves_its_clock() {
+        let mut pool = FarmingPool { schedule: Some(curve()), last_update: 10 * SEC, ..Default::default() };
+        update_farming_pool(&mut pool, 20 * SEC);
+        assert_eq!(pool.acc_reward_per_share, 0);
+        assert_eq!(pool.accrued_fees, 0);
+        assert_eq!(pool.last_update, 20 * SEC);
+    }
+
+    const BUCKET: RateLimitConfig = RateLimitConfig { capacity: 3, refill_per_minute: 2 };
+
+    #[test]
+    fn bucket_refills_one_token_per_interval() {
+        let mut bucket = TokenBucket { tokens: 0, last_refill: 0 };
+        bucket.refill(&BUCKET, 30 * SEC - 1);
+        assert_eq!(bucket.tokens, 0);
+        bucket.refill(&BUCKET, 30 * SEC);
+        assert_eq!(bucket.tokens, 1);
+        assert_eq!(bucket.last_refill, 30 * SEC);
+        // Partial intervals carry over instead of being lost
+        bucket.refill(&BUCKET, 75 * SEC);
+        assert_eq!(bucket.tokens, 2);
+        assert_eq!(bucket.last_refill, 60 * SEC);
+    }
+
+    #[test]
+    fn bucket_refill_stops_at_capacity() {
+        let mut bucket = TokenBucket { tokens: 1, last_refill: 0 };
+        bucket.refill(&BUCKET, 600 * SEC);
+        assert_eq!(bucket.tokens, BUCKET.capacity);
+        assert_eq!(bucket.last_refill, 600 * SEC);
+        assert_eq!(TokenBucket::full(&BUCKET, 5).tokens, BUCKET.capacity);
+    }
+
+    #[test]
+    fn bucket_without_refill_never_recovers() {
+        let config = RateLimitConfig { capacity: 1, refill_per_minute: 0 };
+        let mut bucket = TokenBucket { tokens: 0, last_refill: 0 };
+        bucket.refill(&config, 3_600 * SEC);
+        assert_eq!(bucket.tokens, 0);
+        assert_eq!(bucket.retry_after_secs(&config, 3_600 * SEC), u64::MAX);
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_the_next_token() {
+        let bucket = TokenBucket { tokens: 0, last_refill: 0 };
+        assert_eq!(bucket.retry_after_secs(&BUCKET, 0), 30);
+        assert_eq!(bucket.retry_after_secs(&BUCKET, 29 * SEC + 1), 1);
+        assert_eq!(bucket.retry_after_secs(&BUCKET, 30 * SEC), 0);
+        assert_eq!(bucket.retry_after_secs(&BUCKET, 45 * SEC), 0);
+    }
+}
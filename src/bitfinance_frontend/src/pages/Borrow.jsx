@@ -2,6 +2,7 @@ import React, { useState, useContext, useEffect } from "react";
 import { ArrowRight } from "lucide-react";
 import { bitfinance_backend } from "../../../declarations/bitfinance_backend";
 import { AuthContext } from "../context/AuthContext";
+import { describeCallResult } from "../utils/callResult";
 
 const Borrow = () => {
   const [amount, setAmount] = useState("");
@@ -27,8 +28,8 @@ const Borrow = () => {
   const handleBorrow = async (e) => {
     e.preventDefault();
     try {
-      const result = await bitfinance_backend.borrow_ckbtc(Number(amount));
-      alert(result);
+      const result = await bitfinance_backend.borrow_ckbtc(Number(amount), []);
+      alert(describeCallResult(result));
     } catch (err) {
       alert("Borrow failed: " + err);
     }
@@ -37,8 +38,8 @@ const Borrow = () => {
   const handleRepay = async (e) => {
     e.preventDefault();
     try {
-      const result = await bitfinance_backend.repay_loan_ckbtc(Number(repayAmount));
-      alert(result);
+      const result = await bitfinance_backend.repay_loan_ckbtc(Number(repayAmount), []);
+      alert(describeCallResult(result));
     } catch (err) {
       alert("Repay failed: " + err);
     }
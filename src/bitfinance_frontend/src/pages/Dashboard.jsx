@@ -2,6 +2,7 @@ import React, { useEffect, useState, useContext } from "react";
 import { Wallet, TrendingUp, TrendingDown, Clock, RefreshCw, ArrowDownCircle, ArrowUpCircle, Gift, AlertTriangle } from "lucide-react";
 import { bitfinance_backend } from "../../../declarations/bitfinance_backend";
 import { AuthContext } from "../context/AuthContext";
+import { describeCallResult } from "../utils/callResult";
 
 const Dashboard = () => {
   const [userData, setUserData] = useState(null);
@@ -43,8 +44,8 @@ const Dashboard = () => {
     e.preventDefault();
     setLoading(true);
     try {
-      const result = await bitfinance_backend.deposit_ckbtc(Number(depositAmount));
-      alert(result);
+      const result = await bitfinance_backend.deposit_ckbtc(Number(depositAmount), []);
+      alert(describeCallResult(result));
       setDepositAmount("");
       fetchData();
     } catch (err) {
@@ -57,8 +58,8 @@ const Dashboard = () => {
     e.preventDefault();
     setLoading(true);
     try {
-      const result = await bitfinance_backend.withdraw_ckbtc(Number(withdrawAmount));
-      alert(result);
+      const result = await bitfinance_backend.withdraw_ckbtc(Number(withdrawAmount), []);
+      alert(describeCallResult(result));
       setWithdrawAmount("");
       fetchData();
     } catch (err) {
@@ -70,8 +71,8 @@ const Dashboard = () => {
   const handleClaimStaking = async () => {
     setLoading(true);
     try {
-      const result = await bitfinance_backend.claim_staking_rewards();
-      alert(result);
+      const result = await bitfinance_backend.claim_staking_rewards([], []);
+      alert(describeCallResult(result));
       fetchData();
     } catch (err) {
       alert("Claim staking rewards failed: " + err);
@@ -82,8 +83,8 @@ const Dashboard = () => {
   const handleClaimLending = async () => {
     setLoading(true);
     try {
-      const result = await bitfinance_backend.claim_lending_rewards();
-      alert(result);
+      const result = await bitfinance_backend.claim_lending_rewards([], []);
+      alert(describeCallResult(result));
       fetchData();
     } catch (err) {
       alert("Claim lending rewards failed: " + err);
@@ -94,8 +95,8 @@ const Dashboard = () => {
   const handleClaimFarming = async () => {
     setLoading(true);
     try {
-      const result = await bitfinance_backend.claim_yield_farming_rewards();
-      alert(result);
+      const result = await bitfinance_backend.claim_yield_farming_rewards([], []);
+      alert(describeCallResult(result));
       fetchData();
     } catch (err) {
       alert("Claim farming rewards failed: " + err);
@@ -107,8 +108,8 @@ const Dashboard = () => {
     if (!window.confirm("Are you sure? This will withdraw all your assets!")) return;
     setLoading(true);
     try {
-      const result = await bitfinance_backend.emergency_withdraw_all();
-      alert(result);
+      const result = await bitfinance_backend.emergency_withdraw_all([]);
+      alert(describeCallResult(result));
       fetchData();
     } catch (err) {
       alert("Emergency withdraw failed: " + err);
@@ -2,6 +2,7 @@ import React, { useState, useContext, useEffect } from "react";
 import { ArrowRight } from "lucide-react";
 import { bitfinance_backend } from "../../../declarations/bitfinance_backend";
 import { AuthContext } from "../context/AuthContext";
+import { describeCallResult } from "../utils/callResult";
 
 const Lend = () => {
   const [amount, setAmount] = useState("");
@@ -27,8 +28,8 @@ const Lend = () => {
   const handleLend = async (e) => {
     e.preventDefault();
     try {
-      const result = await bitfinance_backend.lend_ckbtc(Number(amount));
-      alert(result);
+      const result = await bitfinance_backend.lend_ckbtc(Number(amount), []);
+      alert(describeCallResult(result));
     } catch (err) {
       alert("Lend failed: " + err);
     }
@@ -37,8 +38,8 @@ const Lend = () => {
   const handleUnlend = async (e) => {
     e.preventDefault();
     try {
-      const result = await bitfinance_backend.unlend_ckbtc(Number(unlendAmount));
-      alert(result);
+      const result = await bitfinance_backend.unlend_ckbtc(Number(unlendAmount), []);
+      alert(describeCallResult(result));
     } catch (err) {
       alert("Unlend failed: " + err);
     }
@@ -2,6 +2,7 @@ import React, { useContext } from "react";
 import { ArrowRight } from "lucide-react";
 import { bitfinance_backend } from "../../../declarations/bitfinance_backend";
 import { AuthContext } from "../context/AuthContext";
+import { describeCallResult } from "../utils/callResult";
 
 const Register = () => {
   const { principal } = useContext(AuthContext);
@@ -14,7 +15,7 @@ const Register = () => {
     }
     try {
       const result = await bitfinance_backend.register_user();
-      alert(result);
+      alert(describeCallResult(result));
       // Optionally, force a reload or redirect after registration
       // window.location.reload();
       // or use a router to navigate
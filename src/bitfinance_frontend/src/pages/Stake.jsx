@@ -2,6 +2,7 @@ import React, { useState, useContext, useEffect } from "react";
 import { ArrowRight, Shield } from "lucide-react";
 import { bitfinance_backend } from "../../../declarations/bitfinance_backend";
 import { AuthContext } from "../context/AuthContext";
+import { describeCallResult } from "../utils/callResult";
 
 const Stake = () => {
   const [stakeAmount, setStakeAmount] = useState("");
@@ -27,7 +28,7 @@ const Stake = () => {
   const handleStake = async (e) => {
     e.preventDefault();
     try {
-      const result = await bitfinance_backend.stake_ckbtc(Number(stakeAmount));
+      const result = await bitfinance_backend.stake_ckbtc(Number(stakeAmount), [], []);
       alert(result);
     } catch (err) {
       alert("Stake failed: " + err);
@@ -37,7 +38,7 @@ const Stake = () => {
   const handleUnstake = async (e) => {
     e.preventDefault();
     try {
-      const result = await bitfinance_backend.unstake_ckbtc(Number(unstakeAmount));
+      const result = await bitfinance_backend.unstake_ckbtc(Number(unstakeAmount), [], []);
       alert(result);
     } catch (err) {
       alert("Unstake failed: " + err);
@@ -46,8 +47,8 @@ const Stake = () => {
 
   const handleClaim = async () => {
     try {
-      const result = await bitfinance_backend.claim_staking_rewards();
-      alert(result);
+      const result = await bitfinance_backend.claim_staking_rewards([], []);
+      alert(describeCallResult(result));
     } catch (err) {
       alert("Claim failed: " + err);
     }
@@ -2,6 +2,7 @@ import React, { useState, useContext, useEffect } from "react";
 import { ArrowRight, Leaf } from "lucide-react";
 import { bitfinance_backend } from "../../../declarations/bitfinance_backend";
 import { AuthContext } from "../context/AuthContext";
+import { describeCallResult } from "../utils/callResult";
 
 const YieldFarm = () => {
   const [farmAmount, setFarmAmount] = useState("");
@@ -27,7 +28,7 @@ const YieldFarm = () => {
   const handleFarm = async (e) => {
     e.preventDefault();
     try {
-      const result = await bitfinance_backend.yield_farm_ckbtc(Number(farmAmount));
+      const result = await bitfinance_backend.yield_farm_ckbtc(Number(farmAmount), []);
       alert(result);
     } catch (err) {
       alert("Yield farming failed: " + err);
@@ -37,8 +38,8 @@ const YieldFarm = () => {
   const handleUnfarm = async (e) => {
     e.preventDefault();
     try {
-      const result = await bitfinance_backend.unfarm_ckbtc(Number(unfarmAmount));
-      alert(result);
+      const result = await bitfinance_backend.unfarm_ckbtc(Number(unfarmAmount), []);
+      alert(describeCallResult(result));
     } catch (err) {
       alert("Unfarm failed: " + err);
     }
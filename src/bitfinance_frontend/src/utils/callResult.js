@@ -0,0 +1,24 @@
+const sats = (value) => (Number(value) / 100_000_000).toFixed(8);
+
+const describeLimit = (limit) => {
+  if ("BelowMinimumPosition" in limit) {
+    return `Amount is below the minimum position of ${sats(limit.BelowMinimumPosition.minimum)} ckBTC.`;
+  }
+  if ("SupplyCapExceeded" in limit) {
+    return `Supply cap of ${sats(limit.SupplyCapExceeded.cap)} ckBTC reached.`;
+  }
+  if ("BorrowCapExceeded" in limit) {
+    return `Borrow cap of ${sats(limit.BorrowCapExceeded.cap)} ckBTC reached.`;
+  }
+  return `Your borrow limit of ${sats(limit.UserBorrowLimitExceeded.limit)} ckBTC would be exceeded.`;
+};
+
+// Turns a `variant { Ok : text; Err : CallError }` reply into a message for the user
+export const describeCallResult = (result) => {
+  if ("Ok" in result) return result.Ok;
+  if ("RateLimited" in result.Err) {
+    return `Too many requests. Please try again in ${result.Err.RateLimited.retry_after} seconds.`;
+  }
+  if ("Limit" in result.Err) return describeLimit(result.Err.Limit);
+  return result.Err.Rejected;
+};